@@ -6,12 +6,12 @@ import numpy as np
 
 
 def preprocess(file_dir: str) -> str:
-    '''Removes all irrelevant information from the given genome file'''
+    '''Removes all irrelevant information from the given genome file, keeping the > headers that name each segment'''
     
     with open(file_dir, 'r') as file:
         with open(file_dir + ".copy", 'w') as file1:
             for line in file.readlines():
-                if not line.startswith('>'): #all irrelevant lines start with >
+                if line.strip(): #blank lines carry nothing
                     file1.write(line)
     return file_dir + ".copy"
 
@@ -130,6 +130,7 @@ def main():
      
     os.makedirs(basedir + "genomes") #create the genomes folder
     zipdir = tempfile.mkdtemp() #create a temporary folder to unzip our genomes
+    metadata = [] #genome name and flags, one line per genome
     
     # iterate through all files in the folder
     for file in os.listdir(genomes_raw_dir):
@@ -142,11 +143,15 @@ def main():
         
         # retrieve the organism name
         organism_name = "default"
+        assembly_level = ""
         with open(zipdir + f"/{file}/ncbi_dataset/data/assembly_data_report.jsonl") as file: #open the file containing the organism name
             for line in file.readlines():
                 if "organismName" in line:
                     substr = line[line.find("organismName") + 15:]
                     organism_name = substr[:substr.find('"')].replace(" ", "_").replace("/", "_") #extract and perform input validation
+                if "assemblyLevel" in line:
+                    substr = line[line.find("assemblyLevel") + 16:]
+                    assembly_level = substr[:substr.find('"')]
         
         
         # iterate through all files in the extracted location (the file ends with .fna but the name could be anything)
@@ -168,6 +173,14 @@ def main():
             
             genome_file = preprocess(genome_location + tfile) #preprocess the file before copying
             shutil.copyfile(genome_file, folder_loc + "/" + tfile) #copy the file into the output
+
+        # anything short of a complete genome is placed by local alignment
+        flags = [] if assembly_level in ("", "Complete Genome") else ["partial"]
+        metadata.append(os.path.basename(folder_loc) + "\t" + ",".join(flags))
+
+    # kept outside the genomes folder, which only holds one folder per genome
+    with open(basedir + "metadata.tsv", "w") as file:
+        file.write("\n".join(metadata) + "\n")
     
 
 if __name__ == "__main__":
@@ -1,24 +1,65 @@
-use std::{fs::{self, File}, io::{Read, BufReader}, os::unix::prelude::FileExt, collections::HashMap};
-use rand::Rng;
+use std::{fs, path::Path, collections::{BTreeSet, HashMap, HashSet, VecDeque}};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use crate::{errors::PhyloError, structs::{Genome, TreeNode, TreeVertex}};
+use crate::{log_trace, bases, dust, errors::PhyloError, sampling::{Replacement, WeightedSampler}, segments::{self, Segment}, spectrum::KmerSimilarity, structs::{Genome, TreeNode, TreeVertex}};
 
-/// Calculate the Levenshtein distance between two strings
-pub fn levenshtein(first: &str, second: &str) -> usize {
+/// How the kmers standing for a genome are picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmerSelection {
+    Minimizer,  // the kmer with the lowest hash in every window, the same sequence always gives the same kmers
+    Random,     // kmers at random offsets, drawn with the seed of the options
+}
+impl KmerSelection {
+
+    /// Parse a selection name as given on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "minimizer" => Some(KmerSelection::Minimizer),
+            "random" => Some(KmerSelection::Random),
+            _ => None,
+        }
+    }
+}
+
+
+/// How kmers are sampled from a genome, and how the descent compares genomes through them
+#[derive(Debug, Clone, Copy)]
+pub struct KmerOptions {
+    pub selection: KmerSelection, // how kmers are picked
+    pub k: u32,             // length of every kmer
+    pub window: u32,        // number of consecutive kmers each minimizer is picked from
+    pub count: u32,         // number of kmers sampled per genome when picking at random
+    pub skip_n: bool,       // never sample a kmer overlapping an N, so scaffold gaps don't look like mutations
+    pub mask: bool,         // never sample a kmer overlapping a low-complexity region, so shared repeats aren't rewarded
+    pub similarity: KmerSimilarity, // how the descent scores candidates, through sampled kmers, whole spectra or sketches
+    pub sketch_k: u32,      // length of the kmers in a genome's sketch
+    pub scaled: u32,        // one in this many kmer hashes is kept in a genome's sketch
+    pub seed: u64,          // seed for the kmers sampled at random and the genomes sampled during the descent
+}
+impl Default for KmerOptions {
+    fn default() -> Self {
+        KmerOptions { selection: KmerSelection::Minimizer, k: 12, window: 64, count: 64, skip_n: false, mask: true, similarity: KmerSimilarity::Sampled, sketch_k: 21, scaled: 100, seed: 0 }
+    }
+}
+
+
+/// Calculate the Levenshtein distance between two sequences
+///
+/// Ambiguity codes match every base they may stand for, so an N never counts as an edit.
+pub fn levenshtein(first: &[u8], second: &[u8]) -> usize {
     let long: &[u8];
     let short: &[u8];
 
     // first figure out the longest and shortest strings
     if first.len() > second.len() {
-        long = first.as_bytes();
-        short = second.as_bytes();
+        long = first;
+        short = second;
     } else {
-        short = first.as_bytes();
-        long = second.as_bytes();
+        short = first;
+        long = second;
     }
 
     // get the sizes of both strings
-    let longd = long.len();
     let shortd = short.len();
 
     // initialize the two vectors we need, sized according to short to reduce mem usage
@@ -26,8 +67,8 @@ pub fn levenshtein(first: &str, second: &str) -> usize {
     let mut cur = vec![0; short.len() + 1];
 
     // initialize all elements in the starter vector
-    for i in 0..shortd {
-        prev[i] = i;
+    for (i, elem) in prev.iter_mut().enumerate() {
+        *elem = i;
     }
 
     // declare variables outside loop so we don't have to reallocate them
@@ -36,7 +77,7 @@ pub fn levenshtein(first: &str, second: &str) -> usize {
     let mut sub_cost;
 
     // iterate once for every letter in the long word
-    for y in 0..longd {
+    for (y, long_char) in long.iter().enumerate() {
         cur[0] = y+1; //set the character index
 
         // iterate once for every letter in the short word (size of the arrays)
@@ -45,75 +86,39 @@ pub fn levenshtein(first: &str, second: &str) -> usize {
             ins_cost = cur[x] + 1;      // generate cost of insertion
 
             // generate cost of substitution
-            if long[x+1] == short[x] {
+            if bases::compatible(*long_char, short[x]) {
                 sub_cost = prev[x];
             } else {
                 sub_cost = prev[x] + 1;
             }
 
             // insert the minimum cost into the array
-            cur[x+1] = if del_cost < ins_cost && del_cost < sub_cost {
-                del_cost
-            } else if ins_cost < del_cost && ins_cost < sub_cost {
-                ins_cost
-            } else {
-                sub_cost
-            }
+            cur[x+1] = del_cost.min(ins_cost).min(sub_cost);
         }
 
         // move the current vector to the prev location so that it can be looked at next iteration
         std::mem::swap(&mut cur, &mut prev);
     }
 
-    return prev[shortd];
+    prev[shortd]
     
 }
 
 
-/// Given a list of numbers and weights, choose a random element; if limitless is false then the probability acts like a limit
-pub fn random_weighted(elems: Vec<u32>, probabilities: Vec<u32>, rounds: u32, limitless: bool) -> Vec<u32> {
-
-    // return if input is invalid
-    if elems.len() != probabilities.len() {
-        dbg!("EXITING EARLY");
-        return Vec::new();
-    }
-
-    // setup the amounts vector
-    let mut amounts: Vec<u32> = Vec::new();
-    for i in 0..elems.len() { //for each element,
-        for _ in 0..probabilities[i] { //for the amount of weight it has,
-            amounts.push(elems[i]); //push it to the amounts vector
-        }
-    }
-
-    // ensure our number of rounds works if we're not going limitless
-    if !limitless && rounds as usize > amounts.len() {
-        dbg!("EXITING EARLY HERE BECAUSE NOT LIMITLESS AND REQUESTED MORE ROUNDS THAN WEIGHTS");
-        dbg!(amounts.len());
-        dbg!(rounds);
-        dbg!(elems);
-        dbg!(probabilities);
-        return Vec::new();
-    }
-
-    let mut ret: Vec<u32> = Vec::new();
-    let mut rng = rand::thread_rng();
-    let mut gen;
-
-    // start the picking loop
-    for _ in 0..rounds {
-        gen = rng.gen_range(0..amounts.len()); //generate our random index
-        
-        if limitless {
-            ret.push(amounts[gen]);
-        } else {
-            ret.push(amounts.remove(gen));
-        }
-    }
+/// Given a list of numbers and weights, choose random elements; if limitless is false then the weights act like a limit
+pub fn random_weighted<R: Rng + ?Sized>(elems: Vec<u32>, probabilities: Vec<u32>, rounds: u32, limitless: bool, rng: &mut R) -> Result<Vec<u32>, PhyloError> {
+    let sampler = WeightedSampler::new(elems, probabilities)?;
+    let replacement = if limitless { Replacement::With } else { Replacement::Without };
+    sampler.sample(rng, rounds, replacement)
+}
 
-    ret
 
+/// Random number generator for the choices made about one genome, the same for a given seed and name
+///
+/// Every genome gets its own stream, so a build with the same seed is repeated exactly, whatever order
+/// or thread the genomes are handled in.
+pub fn genome_rng(seed: u64, name: &str) -> StdRng {
+    StdRng::seed_from_u64(seed ^ kmer_hash(name.as_bytes()))
 }
 
 
@@ -122,11 +127,7 @@ pub fn vec_to_dict(elems: Vec<u32>) -> HashMap<u32, u32> {
     let mut ret = HashMap::new();
 
     for elem in elems {
-        if ret.contains_key(&elem) {
-            *ret.get_mut(&elem).unwrap() += 1;
-        } else {
-            ret.insert(elem, 1);
-        }
+        *ret.entry(elem).or_insert(0) += 1;
     }
     
     ret
@@ -140,54 +141,165 @@ pub fn file_size(dir: &str) -> Result<u64, PhyloError> {
 
 
 /// Generates k-mers (n-grams) for the given file
-pub fn generate_kmers(file_dir: &str, k: u32, num: u32) -> Result<Vec<String>, PhyloError> {
-    let size = file_size(file_dir)? as usize;
-    let mut ret = Vec::with_capacity(size);     // create a vector with the right size
-    let mut loc: Vec<usize> = Vec::with_capacity(num as usize);     // decide all locations to generate kmers at
-    let mut rng = rand::thread_rng();
+///
+/// Kmers are drawn from within a single segment, never across a header or the border of two segments.
+/// With skip_n set they are also drawn from between runs of N, and with mask set from outside the
+/// low-complexity regions `dust::mask` finds. Genomes made of nothing but low-complexity sequence are
+/// sampled as if mask wasn't set.
+pub fn generate_kmers(file_dir: &str, options: &KmerOptions) -> Result<Vec<String>, PhyloError> {
+    let k: usize = options.k.try_into().map_err(|_| PhyloError::KTooBig(options.k))?;
+    let segments = segments::read_segments(file_dir)?;
+    let mut stretches = kmer_stretches(&segments, k, options.skip_n, options.mask);
+    if options.mask && stretches.iter().all(|s| s.2 == 0) {
+        stretches = kmer_stretches(&segments, k, options.skip_n, false);
+    }
+    let total: usize = stretches.iter().map(|s| s.2).sum();
 
     // Ensure the file is sized satisfactorily
-    if size < k as usize {
+    if total == 0 {
         return Err(PhyloError::FileTooSmall(String::from(file_dir)));
     }
 
-    // Find all locations we'll take kmers at
-    for _ in 0..num {
-        loc.push(rng.gen_range(0..(size - k as usize - 1)));
+    let picked = match options.selection {
+        KmerSelection::Minimizer => minimizers(&segments, &stretches, k, options.window.max(1) as usize),
+        KmerSelection::Random => {
+            let name = Path::new(file_dir).file_name().and_then(|n| n.to_str()).unwrap_or(file_dir); //the same file sampled through another path gets the same kmers
+            random_kmers(&segments, &stretches, k, total, options.count, &mut genome_rng(options.seed, name))
+        },
+    };
+    picked.into_iter()
+        .map(|kmer| String::from_utf8(kmer.to_vec()).map_err(|_| PhyloError::FileReadError(String::from(file_dir))))
+        .collect()
+}
+
+
+/// Kmers at random offsets over all stretches
+fn random_kmers<'a, R: Rng + ?Sized>(segments: &'a [Segment], stretches: &[(usize, usize, usize)], k: usize, total: usize, count: u32, rng: &mut R) -> Vec<&'a [u8]> {
+    let mut ret = Vec::with_capacity(count as usize);
+
+    // For each kmer, pick a start over all stretches then find the stretch it falls in
+    for _ in 0..count {
+        let mut loc = rng.gen_range(0..total);
+        let mut i = 0;
+        while loc >= stretches[i].2 {
+            loc -= stretches[i].2;
+            i += 1;
+        }
+        let (segment, offset, _) = stretches[i];
+        ret.push(&segments[segment].sequence[offset+loc..offset+loc+k]);
     }
-    loc.sort();
 
-    // Prepare the file and buffer
-    let file = File::open(file_dir).map_err(|_| PhyloError::FileOpenError(String::from(file_dir)))?; // Open the genome file
-    let mut buffer: Vec<u8> = vec![0; k.try_into().map_err(|_| PhyloError::KTooBig(k))?];
+    ret
+}
+
 
-    // For each kmer
-    for i in 0..num {
-        file.read_exact_at(&mut buffer, loc[i as usize] as u64).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?; // Read from the specified location
-        ret.push(String::from_utf8(buffer.clone()).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?);    // Push to return vector
+/// The distinct minimizers of every stretch, in the order they're first found
+///
+/// Every run of window consecutive kmers contributes the kmer with the lowest hash, the leftmost one on
+/// ties, so two genomes sharing a stretch at least a window long share its minimizers. A deque of
+/// candidates with rising hashes keeps this linear in the length of the genome.
+fn minimizers<'a>(segments: &'a [Segment], stretches: &[(usize, usize, usize)], k: usize, window: usize) -> Vec<&'a [u8]> {
+    let mut ret = Vec::new();
+    let mut seen = HashSet::new();
+    for &(segment, offset, count) in stretches {
+        let kmers: Vec<&[u8]> = (0..count).map(|i| &segments[segment].sequence[offset+i..offset+i+k]).collect();
+        let hashes: Vec<u64> = kmers.iter().map(|kmer| kmer_hash(kmer)).collect();
+        let mut candidates: VecDeque<usize> = VecDeque::new();
+        for i in 0..count {
+            while candidates.back().is_some_and(|&j| hashes[j] > hashes[i]) {
+                candidates.pop_back();
+            }
+            candidates.push_back(i);
+            if candidates[0] + window <= i { //the oldest candidate left the window
+                candidates.pop_front();
+            }
+            if i + 1 >= window.min(count) && seen.insert(kmers[candidates[0]]) { //stretches shorter than a window still give one
+                ret.push(kmers[candidates[0]]);
+            }
+        }
     }
+    ret
+}
 
-    Ok(ret)
+
+/// Hash of a kmer that stays the same between runs, unlike the hasher of a HashMap
+pub fn kmer_hash(kmer: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325; //FNV-1a
+    for &b in kmer {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33; //finish with the murmur3 mixer, so close kmers don't get close hashes
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+
+/// Stretches of the segments kmers can be drawn from, as (segment, offset, number of kmers it holds)
+pub fn kmer_stretches(segments: &[Segment], k: usize, skip_n: bool, mask: bool) -> Vec<(usize, usize, usize)> {
+    let mut ret = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let masked = if mask { dust::mask(&segment.sequence) } else { vec![false; segment.sequence.len()] };
+        let blocked: Vec<bool> = segment.sequence.iter().zip(masked).map(|(b, m)| m || (skip_n && *b == b'N')).collect();
+        let mut offset = 0;
+        for part in blocked.split(|blocked| *blocked) {
+            ret.push((i, offset, (part.len() + 1).saturating_sub(k)));
+            offset += part.len() + 1;
+        }
+    }
+    ret
 }
 
 
 /// Check how many kmers apply to the given genome
 pub fn kmer_similarity(host: &Genome, guest: &Genome) -> u32 {
-    let mut ret = 0;
-    let kmers = host.kmers.clone();
-   
-    // open file
-    let file = File::open(&guest.dir).unwrap();
-    let mut reader = BufReader::new(file);
+    let all = segments::sequence_text(&guest.dir).unwrap();
+    kmer_hits(&host.kmers, &all)
+}
 
-    // read all data
-    let mut buff = Vec::new();
-    reader.read_to_end(&mut buff).unwrap();
-    let all = String::from_utf8(buff).unwrap();
+
+/// Count how many of the kmers appear in an already loaded sequence
+///
+/// Ambiguity codes match every base they may stand for, except for N in the sequence, since a run of N
+/// would otherwise hold every kmer there is. Kmers holding ambiguity codes, and the windows of the
+/// sequence holding one, are expanded into the plain kmers they may stand for and looked up exactly like
+/// the rest, only those standing for too many are matched base by base. Long lists of kmers, such as the
+/// minimizers of a genome, are looked up in a set of every kmer of the sequence instead of being searched
+/// for one by one.
+pub fn kmer_hits(kmers: &[String], sequence: &str) -> u32 {
+    let bytes = sequence.as_bytes();
+    let k = kmers.first().map(|f| f.len()).unwrap_or(0);
+    let compatible = |kmer: &[u8], window: &[u8]| window.iter().zip(kmer).all(|(&s, &c)| s != b'N' && bases::compatible(s, c));
+
+    // the windows an ambiguity code other than N falls in, windows holding an N can't match anyway
+    let mut starts = BTreeSet::new();
+    if k > 0 && bytes.len() >= k {
+        for (i, _) in bytes.iter().enumerate().filter(|(_, b)| **b != b'N' && bases::is_ambiguous(**b)) {
+            starts.extend((i + 1).saturating_sub(k)..=i.min(bytes.len() - k));
+        }
+    }
+    let mut expanded = HashSet::new();
+    let mut unexpanded = Vec::new();
+    for window in starts.into_iter().map(|s| &bytes[s..s + k]).filter(|w| !w.contains(&b'N')) {
+        match expand(window) {
+            Some(variants) => expanded.extend(variants),
+            None => unexpanded.push(window),
+        }
+    }
+    let windows: Option<HashSet<&[u8]>> = (kmers.len() > 64).then(|| bytes.windows(k).collect());
 
     // for every kmer
-    for i in 0..kmers.len() {
-        if all.contains(&kmers[i]) {
+    let mut ret = 0;
+    for kmer in kmers {
+        let found = match expand(kmer.as_bytes()) {
+            Some(variants) => variants.iter().any(|v| expanded.contains(v) || match &windows {
+                Some(windows) => windows.contains(v.as_slice()),
+                None => std::str::from_utf8(v).is_ok_and(|v| sequence.contains(v)),
+            }) || unexpanded.iter().any(|w| compatible(kmer.as_bytes(), w)),
+            None => bytes.windows(kmer.len()).any(|w| compatible(kmer.as_bytes(), w)),
+        };
+        if found {
             ret += 1;
         }
     }
@@ -196,19 +308,37 @@ pub fn kmer_similarity(host: &Genome, guest: &Genome) -> u32 {
 }
 
 
+/// Every plain kmer a kmer holding ambiguity codes may stand for, or None when there are more than 64
+fn expand(kmer: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut ret = vec![Vec::with_capacity(kmer.len())];
+    for &b in kmer {
+        let options: Vec<u8> = if bases::is_ambiguous(b) { b"ACGT".iter().copied().filter(|c| bases::compatible(*c, b)).collect() } else { vec![b] };
+        if ret.len() * options.len() > 64 {
+            return None;
+        }
+        ret = ret.into_iter().flat_map(|v| options.iter().map(move |o| {
+            let mut v = v.clone();
+            v.push(*o);
+            v
+        })).collect();
+    }
+    Some(ret)
+}
+
+
 /// Retrieve a genome from the tree
-pub fn retrieve_genome<'a>(root: &'a mut TreeNode, path: &Vec<u8>) -> Result<&'a mut Genome, PhyloError> {
+pub fn retrieve_genome<'a>(root: &'a mut TreeNode, path: &[u8]) -> Result<&'a mut Genome, PhyloError> {
     if root.id != path[0] {
         return Err(PhyloError::SearchGenomeError(String::from("Root ID was not 0")));
     }
 
-    let mut paths = path.clone(); //prepare for looping
+    let mut paths = path.to_vec(); //prepare for looping
     let mut cur = root;
     paths.reverse();
     paths.remove(paths.len()-1);
 
     // iterate once per item in the path
-    'path_loop: while paths.len() > 0 {
+    'path_loop: while !paths.is_empty() {
         match &mut cur.vertex {
             TreeVertex::Floor(f) => { //we hit a floor
                 if paths.len() == 1 { //if we hit a floor and we only have one index left
@@ -218,9 +348,9 @@ pub fn retrieve_genome<'a>(root: &'a mut TreeNode, path: &Vec<u8>) -> Result<&'a
                 }
             },
             TreeVertex::Split(s) => { //we hit a split
-                for i in 0..s.len() { //iterate through every node in this split
-                    if s[i].id == paths[paths.len()-1] { //if we found the next node in the path
-                        cur = &mut s[i];
+                for node in s.iter_mut() { //iterate through every node in this split
+                    if node.id == paths[paths.len()-1] { //if we found the next node in the path
+                        cur = node;
                         paths.remove(paths.len()-1);
                         continue 'path_loop; //
                     }
@@ -243,7 +373,7 @@ pub fn get_full_path<'a>(root: &'a TreeNode, path: &Vec<u8>) -> Result<Vec<&'a T
     }
 
     ret.push(root);
-    println!("FULL PATH: PATH RECEIVED: {:?}", &path);
+    log_trace!("full_path: path received {:?}", path);
 
     // for each part of the path, find the node that corresponds to it and push it to the return vector
     let mut cur = root;
@@ -252,7 +382,7 @@ pub fn get_full_path<'a>(root: &'a TreeNode, path: &Vec<u8>) -> Result<Vec<&'a T
         // find what type of vertex we're working with
         match &cur.vertex {
             TreeVertex::Split(s) => { //split found
-                println!("FOUND SPLIT AT {}, i is {}, cur is {}", i, &path[i], &cur.id);
+                log_trace!("full_path: found split at {}, expected {}, is {}", i, path[i], cur.id);
                 if i >= path.len()-2 { //too close to the edge
                     return Err(PhyloError::SearchNodeError(String::from("full_path: Found a split instead of a floor (split was later than expected)")));
                 }
@@ -270,7 +400,7 @@ pub fn get_full_path<'a>(root: &'a TreeNode, path: &Vec<u8>) -> Result<Vec<&'a T
 
             },
             TreeVertex::Floor(_) => { //floor found
-                println!("FOUND FLOOR AT {}, should be {}, is {}", i, &path[i], &cur.id);
+                log_trace!("full_path: found floor at {}, expected {}, is {}", i, path[i], cur.id);
                 if i != path.len() - 2 { //if this isn't the second-to-last index
                     return Err(PhyloError::SearchNodeError(String::from("full_path: Found a floor instead of a split (floor was earlier than expected)")));
                 }
@@ -288,12 +418,12 @@ pub fn get_mut_node_and_increment<'a>(root: &'a mut TreeNode, path: &Vec<u8>) ->
         return Err(PhyloError::SearchNodeError(String::from("get_node: Root ID doesn't match with expected value")));
     }
 
-    println!("PATH RECEIVED: {:?}", &path);
+    log_trace!("get_node: path received {:?}", path);
 
     // for each part of the path, find the node that corresponds to it and push it to the return vector
     let mut cur = root;
-    cur.count = cur.count + 1;
-    println!("UPDATED: {}", cur.id);
+    cur.count += 1;
+    log_trace!("get_node: incremented {}", cur.id);
 
     'main_loop: for i in 1..path.len()-1 { //exclude the last index
         
@@ -308,8 +438,8 @@ pub fn get_mut_node_and_increment<'a>(root: &'a mut TreeNode, path: &Vec<u8>) ->
                 for node in s {
                     if node.id == path[i] { //found the next node
                         cur = node; // This was a mutable ref to a mutable ref; not what you're looking for
-                        cur.count = cur.count + 1; //increment the count
-                        println!("UPDATED: {}", cur.id);
+                        cur.count += 1; //increment the count
+                        log_trace!("get_node: incremented {}", cur.id);
                         continue 'main_loop;
                     }
                     continue;
@@ -326,11 +456,87 @@ pub fn get_mut_node_and_increment<'a>(root: &'a mut TreeNode, path: &Vec<u8>) ->
             }
         }
     }
-    cur.count = cur.count - 1; //don't increment the base level of nodes, because the behavior here differs
-    println!("REVERTED: {}", cur.id);
+    cur.count -= 1; //don't increment the base level of nodes, because the behavior here differs
+    log_trace!("get_node: reverted {}", cur.id);
     Ok(cur) 
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn kmers(list: &[&str]) -> Vec<String> {
+        list.iter().map(|k| String::from(*k)).collect()
+    }
+
+    #[test]
+    fn kmer_hits_exact() {
+        assert_eq!(kmer_hits(&kmers(&["ACG", "TTT", "GGG"]), "AACGT\nTT"), 1); //no kmer spans two segments
+    }
+
+    #[test]
+    fn kmer_hits_ambiguity_codes() {
+        // R stands for A or G, N in the sequence matches nothing
+        let sequence = "AACGNNTTR";
+        assert_eq!(kmer_hits(&kmers(&["TTA", "TTG", "TTC", "CGN", "NNN"]), sequence), 3);
+
+        // a long list goes through the set of windows, and must agree
+        let mut long = vec![String::from("TTA"); 100];
+        long.extend(kmers(&["TTC", "RYN", "CCC"]));
+        assert_eq!(kmer_hits(&long, sequence), 101);
+
+        // every window of BDHV stands for 81 kmers, too many to expand, so they're matched base by base
+        assert_eq!(kmer_hits(&kmers(&["CACA", "GGGG", "TTTT"]), "AAAANNNNBDHVBDHV"), 1);
+    }
+
+    #[test]
+    fn expand_ambiguity_codes() {
+        assert_eq!(expand(b"ARY"), Some(vec![b"AAC".to_vec(), b"AAT".to_vec(), b"AGC".to_vec(), b"AGT".to_vec()]));
+        assert_eq!(expand(b"NNNN").map(|v| v.len()), None);
+    }
+
+    fn one_segment(sequence: &str) -> Vec<Segment> {
+        vec![Segment { name: String::from("1"), sequence: sequence.as_bytes().to_vec() }]
+    }
 
+    fn genome_minimizers(sequence: &str, k: usize, window: usize) -> Vec<Vec<u8>> {
+        let segments = one_segment(sequence);
+        let stretches = kmer_stretches(&segments, k, true, false);
+        minimizers(&segments, &stretches, k, window).into_iter().map(|m| m.to_vec()).collect()
+    }
+
+    #[test]
+    fn stretches_stop_at_n() {
+        assert_eq!(kmer_stretches(&one_segment("ACGTNACGTA"), 3, true, false), vec![(0, 0, 2), (0, 5, 3)]);
+        assert_eq!(kmer_stretches(&one_segment("ACGTNACGTA"), 3, false, false), vec![(0, 0, 8)]);
+    }
+
+    #[test]
+    fn shared_stretches_share_minimizers() {
+        let shared = "GCTAAAGACAATTACATAACATACACGTCAGCACGAAACTTGTTGGCCCAGTG";
+        let inner = genome_minimizers(shared, 5, 4);
+        let outer = genome_minimizers(&format!("TTGACCA{}CCGTA", shared), 5, 4);
+        assert!(inner.len() > 1);
+        assert!(inner.iter().all(|m| outer.contains(m)));
+
+        // a stretch shorter than a window still gives one
+        assert_eq!(genome_minimizers("ACGTAC", 5, 4).len(), 1);
+    }
+
+    #[test]
+    fn random_weighted_needs_weight() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(random_weighted(Vec::new(), Vec::new(), 1, true, &mut rng).is_err());
+        assert!(random_weighted(vec![1, 2], vec![0, 0], 1, false, &mut rng).is_err());
+        assert_eq!(random_weighted(vec![7], vec![1], 3, true, &mut rng).unwrap(), vec![7, 7, 7]);
+        assert!(random_weighted(vec![7], vec![1], 3, false, &mut rng).is_err());
+    }
+
+    #[test]
+    fn levenshtein_compares_every_base() {
+        assert_eq!(levenshtein(b"ACGT", b"ACGA"), 1);
+        assert_eq!(levenshtein(b"GATTACA", b"GTTTACAA"), 2);
+        assert_eq!(levenshtein(b"ACGT", b""), 4);
+    }
+}
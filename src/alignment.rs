@@ -0,0 +1,583 @@
+use std::fmt::{self, Display};
+
+use crate::bases;
+
+
+/// Scores used when aligning two sequences, penalties are given as positive numbers
+///
+/// A gap of length l costs gap_open + l * gap_extend.
+#[derive(Debug, Clone, Copy)]
+pub struct Scoring {
+    pub match_score: i32,   // reward for two identical symbols
+    pub mismatch: i32,      // penalty for two different symbols
+    pub gap_open: i32,      // penalty for starting a gap
+    pub gap_extend: i32,    // penalty for every symbol in a gap
+}
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring { match_score: 2, mismatch: 4, gap_open: 4, gap_extend: 2 }
+    }
+}
+impl Scoring {
+
+    /// Cost of aligning two symbols, the negated score
+    fn cost(&self, a: u8, b: u8) -> i64 {
+        if bases::compatible(a, b) { -(self.match_score as i64) } else { self.mismatch as i64 }
+    }
+
+    /// Cost of a gap of the given length
+    fn gap(&self, length: usize) -> i64 {
+        if length == 0 { 0 } else { self.gap_open as i64 + self.gap_extend as i64 * length as i64 }
+    }
+}
+
+
+/// One column of an alignment, named from the point of view of the reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignOp {
+    Match,      // symbols that may stand for the same base
+    Mismatch,   // symbols that can't
+    Insertion,  // symbol only in the query
+    Deletion,   // symbol only in the reference
+}
+impl AlignOp {
+
+    /// The extended CIGAR letter for this column
+    pub fn letter(&self) -> char {
+        match self {
+            AlignOp::Match => '=',
+            AlignOp::Mismatch => 'X',
+            AlignOp::Insertion => 'I',
+            AlignOp::Deletion => 'D',
+        }
+    }
+}
+
+
+/// Kind of difference between the reference and the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantKind {
+    Snp,
+    Insertion,
+    Deletion,
+}
+impl VariantKind {
+
+    /// Name used when printing variants
+    pub fn label(&self) -> &'static str {
+        match self {
+            VariantKind::Snp => "SNP",
+            VariantKind::Insertion => "INS",
+            VariantKind::Deletion => "DEL",
+        }
+    }
+}
+
+
+/// A single difference between the reference and the query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub kind: VariantKind,
+    pub position: usize,        // 1-based position in the reference, insertions sit after this position
+    pub reference: String,      // reference symbols, empty for insertions
+    pub alternate: String,      // query symbols, empty for deletions
+}
+impl Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_dash = |s: &str| if s.is_empty() { String::from("-") } else { String::from(s) };
+        write!(f, "{}\t{}\t{}\t{}", self.position, self.kind.label(), or_dash(&self.reference), or_dash(&self.alternate))
+    }
+}
+
+
+/// Global alignment between a reference and a query
+#[derive(Debug, Clone)]
+pub struct Alignment {
+    pub score: i64,         // total score under the scoring used
+    pub ops: Vec<AlignOp>,  // one entry per column, in order
+}
+impl Alignment {
+
+    /// Run-length encoded columns, such as 10=1X3I
+    pub fn cigar(&self) -> String {
+        let mut ret = String::new();
+        let mut i = 0;
+        while i < self.ops.len() {
+            let run = self.ops[i..].iter().take_while(|op| **op == self.ops[i]).count();
+            ret.push_str(&format!("{}{}", run, self.ops[i].letter()));
+            i += run;
+        }
+        ret
+    }
+
+    /// Share of the columns that are matches
+    pub fn identity(&self) -> f64 {
+        if self.ops.is_empty() {
+            return 0.0;
+        }
+        self.ops.iter().filter(|op| **op == AlignOp::Match).count() as f64 / self.ops.len() as f64
+    }
+
+    /// Every SNP and indel of the alignment, neighbouring gap columns of the same kind merged into one indel
+    pub fn variants(&self, reference: &[u8], query: &[u8]) -> Vec<Variant> {
+        let mut ret: Vec<Variant> = Vec::new();
+        let (mut r, mut q) = (0, 0); //symbols consumed so far
+        let mut last = AlignOp::Match;
+        for op in &self.ops {
+            match op {
+                AlignOp::Match => {},
+                AlignOp::Mismatch => ret.push(Variant {
+                    kind: VariantKind::Snp,
+                    position: r + 1,
+                    reference: String::from(reference[r] as char),
+                    alternate: String::from(query[q] as char),
+                }),
+                AlignOp::Insertion if last == AlignOp::Insertion => ret.last_mut().unwrap().alternate.push(query[q] as char),
+                AlignOp::Insertion => ret.push(Variant {
+                    kind: VariantKind::Insertion,
+                    position: r,
+                    reference: String::new(),
+                    alternate: String::from(query[q] as char),
+                }),
+                AlignOp::Deletion if last == AlignOp::Deletion => ret.last_mut().unwrap().reference.push(reference[r] as char),
+                AlignOp::Deletion => ret.push(Variant {
+                    kind: VariantKind::Deletion,
+                    position: r + 1,
+                    reference: String::from(reference[r] as char),
+                    alternate: String::new(),
+                }),
+            }
+            if *op != AlignOp::Insertion {
+                r += 1;
+            }
+            if *op != AlignOp::Deletion {
+                q += 1;
+            }
+            last = *op;
+        }
+        ret
+    }
+}
+
+
+/// Optimal global alignment of the query against the reference with affine gaps, in linear memory
+///
+/// This is the Myers-Miller form of Hirschberg's algorithm: the reference is split in half, the best
+/// crossing point of the middle row is found from a forward pass over the top half and a backward
+/// pass over the bottom half, and both halves are aligned recursively. A gap spanning the middle row
+/// is handled by joining the two deletions so it only pays the opening penalty once. Memory grows with
+/// the query length only, at about twice the time of a full table.
+pub fn align(reference: &[u8], query: &[u8], scoring: &Scoring) -> Alignment {
+    let mut aligner = Aligner { scoring, ops: Vec::with_capacity(reference.len().max(query.len())) };
+    aligner.diff(reference, query, scoring.gap_open as i64, scoring.gap_open as i64);
+
+    // score the columns again rather than trust the sum of the recursion
+    let mut score = 0;
+    let (mut r, mut q) = (0, 0);
+    let mut last = AlignOp::Match;
+    for op in &aligner.ops {
+        match op {
+            AlignOp::Match | AlignOp::Mismatch => {
+                score -= scoring.cost(reference[r], query[q]);
+                r += 1;
+                q += 1;
+            },
+            AlignOp::Insertion => {
+                score -= scoring.gap_extend as i64 + if last == AlignOp::Insertion { 0 } else { scoring.gap_open as i64 };
+                q += 1;
+            },
+            AlignOp::Deletion => {
+                score -= scoring.gap_extend as i64 + if last == AlignOp::Deletion { 0 } else { scoring.gap_open as i64 };
+                r += 1;
+            },
+        }
+        last = *op;
+    }
+    Alignment { score, ops: aligner.ops }
+}
+
+
+/// State of a linear memory alignment, the columns are appended in order as the recursion settles them
+struct Aligner<'a> {
+    scoring: &'a Scoring,
+    ops: Vec<AlignOp>,
+}
+impl Aligner<'_> {
+
+    /// Align a against b, where tb and te are the costs of opening a deletion at the start and the end
+    ///
+    /// A zero opening cost means a deletion there continues one from the neighbouring subproblem.
+    fn diff(&mut self, a: &[u8], b: &[u8], tb: i64, te: i64) {
+        let (m, n) = (a.len(), b.len());
+        let (g, h) = (self.scoring.gap_open as i64, self.scoring.gap_extend as i64);
+
+        if n == 0 {
+            self.push(AlignOp::Deletion, m);
+            return;
+        }
+        if m == 0 {
+            self.push(AlignOp::Insertion, n);
+            return;
+        }
+        if m == 1 {
+            // either delete the symbol and insert all of b, or line it up with the best symbol of b
+            let mut best = (tb.min(te) + h + self.scoring.gap(n), None);
+            for (j, &bj) in b.iter().enumerate() {
+                let cost = self.scoring.gap(j) + self.scoring.cost(a[0], bj) + self.scoring.gap(n - j - 1);
+                if cost < best.0 {
+                    best = (cost, Some(j));
+                }
+            }
+            match best.1 {
+                None if tb <= te => {
+                    self.push(AlignOp::Deletion, 1);
+                    self.push(AlignOp::Insertion, n);
+                },
+                None => {
+                    self.push(AlignOp::Insertion, n);
+                    self.push(AlignOp::Deletion, 1);
+                },
+                Some(j) => {
+                    self.push(AlignOp::Insertion, j);
+                    self.push(if bases::compatible(a[0], b[j]) { AlignOp::Match } else { AlignOp::Mismatch }, 1);
+                    self.push(AlignOp::Insertion, n - j - 1);
+                }
+            }
+            return;
+        }
+
+        // forward pass over the top half, backward pass over the bottom half
+        let mid = m / 2;
+        let (cc, dd) = self.last_row(a[..mid].iter(), b.iter(), tb);
+        let (rr, ss) = self.last_row(a[mid..].iter().rev(), b.iter().rev(), te);
+
+        // best place to cross the middle row, either between two columns or inside a deletion
+        let mut best = (i64::MAX, 0, false);
+        for j in 0..=n {
+            let through = cc[j] + rr[n - j];
+            if through < best.0 {
+                best = (through, j, false);
+            }
+            let gap = dd[j] + ss[n - j] - g;
+            if gap < best.0 {
+                best = (gap, j, true);
+            }
+        }
+
+        let (_, j, joined) = best;
+        if joined {
+            self.diff(&a[..mid - 1], &b[..j], tb, 0);
+            self.push(AlignOp::Deletion, 2);
+            self.diff(&a[mid + 1..], &b[j..], 0, te);
+        } else {
+            self.diff(&a[..mid], &b[..j], tb, g);
+            self.diff(&a[mid..], &b[j..], g, te);
+        }
+    }
+
+    /// Costs of aligning all of a against every prefix of b, along with the costs of those ending in a deletion
+    fn last_row<'b>(&self, a: impl Iterator<Item = &'b u8>, b: impl Iterator<Item = &'b u8> + Clone, tb: i64) -> (Vec<i64>, Vec<i64>) {
+        let (g, h) = (self.scoring.gap_open as i64, self.scoring.gap_extend as i64);
+        let n = b.clone().count();
+        let mut cc = vec![0; n + 1]; //best cost
+        let mut dd = vec![0; n + 1]; //best cost ending in a deletion
+        let mut t = g;
+        for j in 1..=n {
+            t += h;
+            cc[j] = t;
+            dd[j] = t + g;
+        }
+
+        let mut t = tb;
+        for &ai in a {
+            let mut s = cc[0]; //diagonal
+            t += h;
+            let mut c = t;
+            cc[0] = c;
+            let mut e = t + g; //best cost ending in an insertion
+            for (j, &bj) in b.clone().enumerate() {
+                e = e.min(c + g) + h;
+                dd[j+1] = dd[j+1].min(cc[j+1] + g) + h;
+                c = dd[j+1].min(e).min(s + self.scoring.cost(ai, bj));
+                s = cc[j+1];
+                cc[j+1] = c;
+            }
+        }
+        dd[0] = cc[0];
+        (cc, dd)
+    }
+
+    /// Append a run of columns of the same kind
+    fn push(&mut self, op: AlignOp, count: usize) {
+        self.ops.extend(std::iter::repeat_n(op, count));
+    }
+}
+
+
+/// Best local alignment between a reference and a query, along with where it starts in both
+#[derive(Debug, Clone)]
+pub struct LocalAlignment {
+    pub alignment: Alignment,   // columns of the aligned region only
+    pub reference_start: usize, // 0-based start of the region in the reference
+    pub query_start: usize,     // 0-based start of the region in the query
+}
+impl LocalAlignment {
+
+    /// Every SNP and indel of the aligned region, positioned in the whole reference
+    pub fn variants(&self, reference: &[u8], query: &[u8]) -> Vec<Variant> {
+        let mut ret = self.alignment.variants(&reference[self.reference_start..], &query[self.query_start..]);
+        for variant in ret.iter_mut() {
+            variant.position += self.reference_start;
+        }
+        ret
+    }
+
+    /// 0-based end of the region in the reference, exclusive
+    pub fn reference_end(&self) -> usize {
+        self.reference_start + self.alignment.ops.iter().filter(|op| **op != AlignOp::Insertion).count()
+    }
+
+    /// 0-based end of the region in the query, exclusive
+    pub fn query_end(&self) -> usize {
+        self.query_start + self.alignment.ops.iter().filter(|op| **op != AlignOp::Deletion).count()
+    }
+}
+
+
+/// Optimal local alignment (Smith-Waterman with affine gaps), in linear memory
+///
+/// A first pass finds where the best scoring region ends, striped over SIMD lanes when simd is set and
+/// the CPU supports it. A second pass over the reversed sequences, anchored at that end, finds where
+/// the region starts, and the region is then aligned globally to recover its columns.
+pub fn local_align(reference: &[u8], query: &[u8], scoring: &Scoring, simd: bool) -> LocalAlignment {
+    let (score, reference_end, query_end) = best_local_end(reference, query, scoring, simd);
+    if score <= 0 {
+        return LocalAlignment { alignment: Alignment { score: 0, ops: Vec::new() }, reference_start: 0, query_start: 0 };
+    }
+
+    let reversed_reference: Vec<u8> = reference[..reference_end].iter().rev().copied().collect();
+    let reversed_query: Vec<u8> = query[..query_end].iter().rev().copied().collect();
+    let (_, reference_length, query_length) = best_cell(&reversed_reference, &reversed_query, scoring, true);
+    let (reference_start, query_start) = (reference_end - reference_length, query_end - query_length);
+
+    let alignment = align(&reference[reference_start..reference_end], &query[query_start..query_end], scoring);
+    LocalAlignment { alignment, reference_start, query_start }
+}
+
+
+/// Score and exclusive end positions of the best local alignment
+fn best_local_end(reference: &[u8], query: &[u8], scoring: &Scoring, simd: bool) -> (i64, usize, usize) {
+    #[cfg(target_arch = "x86_64")]
+    if simd && is_x86_feature_detected!("sse4.1") {
+        return unsafe { striped::best_local_end(reference, query, scoring) }; //the feature was checked just above
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = simd;
+    best_cell(reference, query, scoring, false)
+}
+
+
+/// Best scoring cell of the alignment table, as (score, reference end, query end) with exclusive ends
+///
+/// Local alignments can start anywhere, anchored ones have to start at the beginning of both sequences
+/// but can still end anywhere. Ties go to the cell reached first.
+fn best_cell(reference: &[u8], query: &[u8], scoring: &Scoring, anchored: bool) -> (i64, usize, usize) {
+    let (g, h) = (scoring.gap_open as i64, scoring.gap_extend as i64);
+    let floor = if anchored { i64::MIN / 4 } else { 0 };
+    let border = |length: usize| if anchored { -scoring.gap(length) } else { 0 };
+
+    let mut hh: Vec<i64> = (0..=query.len()).map(border).collect(); //best score ending at each query position
+    let mut ee = vec![i64::MIN / 4; query.len() + 1]; //best score ending in a deletion
+    let mut best = (if anchored { i64::MIN } else { 0 }, 0, 0);
+
+    for (i, &r) in reference.iter().enumerate() {
+        let mut diagonal = hh[0];
+        hh[0] = border(i + 1);
+        let mut left = hh[0];
+        let mut f = i64::MIN / 4; //best score ending in an insertion
+        for (j, &q) in query.iter().enumerate() {
+            ee[j+1] = (ee[j+1] - h).max(hh[j+1] - g - h);
+            f = (f - h).max(left - g - h);
+            let cell = (diagonal - scoring.cost(r, q)).max(ee[j+1]).max(f).max(floor);
+            diagonal = hh[j+1];
+            hh[j+1] = cell;
+            left = cell;
+            if cell > best.0 {
+                best = (cell, i + 1, j + 1);
+            }
+        }
+    }
+    best
+}
+
+
+/// Farrar's striped Smith-Waterman over four 32 bit lanes
+///
+/// The query is split into four equal stretches, one per lane, so the cells updated together never
+/// depend on each other. Insertions that cross from one stretch into the next are fixed up by a second,
+/// usually short, loop over the column.
+#[cfg(target_arch = "x86_64")]
+mod striped {
+    use std::arch::x86_64::*;
+    use super::Scoring;
+
+    const LANES: usize = 4;
+    const NEG: i32 = i32::MIN / 2;
+
+    /// Same result as the scalar best_cell for local alignments
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn best_local_end(reference: &[u8], query: &[u8], scoring: &Scoring) -> (i64, usize, usize) {
+        let n = query.len();
+        if n == 0 || reference.is_empty() {
+            return (0, 0, 0);
+        }
+        let seg_len = n.div_ceil(LANES);
+        let gap_open = _mm_set1_epi32(scoring.gap_open + scoring.gap_extend);
+        let gap_extend = _mm_set1_epi32(scoring.gap_extend);
+        let zero = _mm_setzero_si128();
+
+        // scores of every reference symbol against the query, laid out like the columns
+        let mut index = [usize::MAX; 256];
+        let mut profiles: Vec<Vec<__m128i>> = Vec::new();
+        for &c in reference {
+            let c = c.to_ascii_uppercase();
+            if index[c as usize] != usize::MAX {
+                continue;
+            }
+            index[c as usize] = profiles.len();
+            profiles.push((0..seg_len).map(|i| {
+                let lanes: [i32; LANES] = std::array::from_fn(|k| query.get(k * seg_len + i).map(|q| -scoring.cost(c, *q) as i32).unwrap_or(NEG));
+                _mm_loadu_si128(lanes.as_ptr() as *const __m128i)
+            }).collect());
+        }
+
+        let mut h_store = vec![zero; seg_len];
+        let mut h_load = vec![zero; seg_len];
+        let mut e = vec![_mm_set1_epi32(NEG); seg_len];
+        let mut best = (0, 0, 0);
+
+        for (ri, &c) in reference.iter().enumerate() {
+            let profile = &profiles[index[c.to_ascii_uppercase() as usize]];
+            let mut v_f = _mm_set1_epi32(NEG);
+            let mut v_h = _mm_slli_si128(h_store[seg_len - 1], 4); //diagonal of the first stretch of each lane is the end of the one before
+            std::mem::swap(&mut h_load, &mut h_store);
+            let mut v_max = zero;
+
+            for i in 0..seg_len {
+                v_h = _mm_add_epi32(v_h, profile[i]);
+                v_h = _mm_max_epi32(v_h, e[i]);
+                v_h = _mm_max_epi32(v_h, v_f);
+                v_h = _mm_max_epi32(v_h, zero);
+                v_max = _mm_max_epi32(v_max, v_h);
+                h_store[i] = v_h;
+
+                let v_open = _mm_sub_epi32(v_h, gap_open);
+                e[i] = _mm_max_epi32(_mm_sub_epi32(e[i], gap_extend), v_open);
+                v_f = _mm_max_epi32(_mm_sub_epi32(v_f, gap_extend), v_open);
+                v_h = h_load[i];
+            }
+
+            // carry insertions over into the next lane until they stop mattering
+            v_f = _mm_insert_epi32(_mm_slli_si128(v_f, 4), NEG, 0);
+            let mut i = 0;
+            loop {
+                let v_h = h_store[i];
+                if _mm_movemask_epi8(_mm_cmpgt_epi32(v_f, _mm_sub_epi32(v_h, gap_open))) == 0 {
+                    break;
+                }
+                let v_h = _mm_max_epi32(v_h, v_f);
+                h_store[i] = v_h;
+                v_max = _mm_max_epi32(v_max, v_h);
+                e[i] = _mm_max_epi32(e[i], _mm_sub_epi32(v_h, gap_open));
+                v_f = _mm_sub_epi32(v_f, gap_extend);
+                i += 1;
+                if i == seg_len {
+                    i = 0;
+                    v_f = _mm_insert_epi32(_mm_slli_si128(v_f, 4), NEG, 0);
+                }
+            }
+
+            // only look for where the best cell is when the column beats everything so far
+            let column_max = lanes(v_max).into_iter().max().unwrap() as i64;
+            if column_max > best.0 {
+                if let Some(j) = (0..n).find(|j| lanes(h_store[j % seg_len])[j / seg_len] as i64 == column_max) {
+                    best = (column_max, ri + 1, j + 1);
+                }
+            }
+        }
+        best
+    }
+
+    /// The four lanes of a vector
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn lanes(v: __m128i) -> [i32; LANES] {
+        let mut ret = [0; LANES];
+        _mm_storeu_si128(ret.as_mut_ptr() as *mut __m128i, v);
+        ret
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(kind: VariantKind, position: usize, reference: &str, alternate: &str) -> Variant {
+        Variant { kind, position, reference: String::from(reference), alternate: String::from(alternate) }
+    }
+
+    #[test]
+    fn identical_sequences() {
+        let alignment = align(b"ACGT", b"ACGT", &Scoring::default());
+        assert_eq!(alignment.cigar(), "4=");
+        assert_eq!(alignment.score, 8);
+        assert_eq!(alignment.identity(), 1.0);
+    }
+
+    #[test]
+    fn snp() {
+        let (reference, query) = (b"ACGTACGT", b"ACGAACGT");
+        let alignment = align(reference, query, &Scoring::default());
+        assert_eq!(alignment.cigar(), "3=1X4=");
+        assert_eq!(alignment.score, 7 * 2 - 4);
+        assert_eq!(alignment.variants(reference, query), vec![variant(VariantKind::Snp, 4, "T", "A")]);
+    }
+
+    #[test]
+    fn indels_are_merged() {
+        // a gap of four pays the opening penalty once
+        let (reference, query) = (b"AAAACCCCGGGG", b"AAAAGGGG");
+        let alignment = align(reference, query, &Scoring::default());
+        assert_eq!(alignment.cigar(), "4=4D4=");
+        assert_eq!(alignment.score, 8 * 2 - (4 + 4 * 2));
+        assert_eq!(alignment.variants(reference, query), vec![variant(VariantKind::Deletion, 5, "CCCC", "")]);
+
+        let (reference, query) = (b"AAAAGGGG", b"AAAACCGGGG");
+        let alignment = align(reference, query, &Scoring::default());
+        assert_eq!(alignment.cigar(), "4=2I4=");
+        assert_eq!(alignment.variants(reference, query), vec![variant(VariantKind::Insertion, 4, "", "CC")]);
+    }
+
+    #[test]
+    fn ambiguity_codes_match() {
+        assert_eq!(align(b"ACGT", b"RCGN", &Scoring::default()).cigar(), "4=");
+    }
+
+    #[test]
+    fn local_region() {
+        let (reference, query) = (b"TTTTTTACGTACGTGGGGGG", b"ACGTTCGT");
+        for simd in [false, true] {
+            let local = local_align(reference, query, &Scoring::default(), simd);
+            assert_eq!((local.reference_start, local.reference_end()), (6, 14));
+            assert_eq!((local.query_start, local.query_end()), (0, 8));
+            assert_eq!(local.alignment.cigar(), "4=1X3=");
+            assert_eq!(local.variants(reference, query), vec![variant(VariantKind::Snp, 11, "A", "T")]);
+        }
+    }
+
+    #[test]
+    fn unrelated_sequences_have_no_local_region() {
+        let local = local_align(b"AAAAAAAA", b"CCCCCCCC", &Scoring::default(), false);
+        assert!(local.alignment.ops.is_empty());
+    }
+}
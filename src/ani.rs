@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use crate::bases;
+
+
+/// Length of the fragments the query is cut into
+pub const FRAGMENT: usize = 1000;
+
+/// Length of the kmers anchoring a fragment on the reference
+const ANCHOR_K: usize = 16;
+
+/// Kmers found more often than this in the reference are repeats and anchor nothing
+const MAX_OCCURRENCES: usize = 8;
+
+/// Fewest anchors on one diagonal for a fragment to be aligned
+const MIN_ANCHORS: u32 = 3;
+
+/// How far the alignment of a fragment may stray from the diagonal its anchors picked
+const BAND: usize = 64;
+
+/// Lowest identity at which a fragment counts as mapped
+pub const MIN_IDENTITY: f64 = 0.7;
+
+
+/// Average nucleotide identity of a query against a reference
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AniEstimate {
+    pub ani: f64,               // mean identity of the mapped fragments, 0 if none mapped
+    pub aligned_fraction: f64,  // fraction of the fragmented query covered by mapped fragments
+    pub fragments: usize,       // fragments the query was cut into
+    pub mapped: usize,          // fragments that mapped onto the reference
+}
+
+
+/// Estimate the identity of the query to the reference, the way FastANI and ANIb do
+///
+/// The query is cut into fragments, and every fragment is anchored on the reference by the diagonal most
+/// of its shared kmers agree on, on whichever strand gives more anchors. The fragment is then aligned
+/// within a band around that diagonal, and counts as mapped if its identity reaches `MIN_IDENTITY`. A
+/// tail shorter than half a fragment is left out, unless the query is that short, and isn't counted in
+/// the aligned fraction either.
+pub fn ani(query: &[u8], reference: &[u8]) -> AniEstimate {
+    let index = anchor_index(reference);
+    let fragments: Vec<&[u8]> = query.chunks(FRAGMENT).filter(|f| f.len() >= FRAGMENT / 2 || f.len() == query.len()).collect();
+    let mut ret = AniEstimate { fragments: fragments.len(), ..Default::default() };
+    let total: usize = fragments.iter().map(|f| f.len()).sum();
+    let (mut identities, mut covered) = (0.0, 0);
+
+    for fragment in fragments {
+        let reverse: Vec<u8> = fragment.iter().rev().map(|b| bases::complement(*b)).collect();
+        let forward_anchor = best_diagonal(fragment, &index);
+        let reverse_anchor = best_diagonal(&reverse, &index);
+        let (strand, anchor) = if reverse_anchor.map(|a| a.1) > forward_anchor.map(|a| a.1) { (&reverse[..], reverse_anchor) } else { (fragment, forward_anchor) };
+        let Some((diagonal, _)) = anchor else {
+            continue;
+        };
+        let identity = banded_identity(strand, reference, diagonal);
+        if identity >= MIN_IDENTITY {
+            ret.mapped += 1;
+            identities += identity;
+            covered += fragment.len();
+        }
+    }
+    if ret.mapped > 0 {
+        ret.ani = identities / ret.mapped as f64;
+        ret.aligned_fraction = covered as f64 / total as f64;
+    }
+    ret
+}
+
+
+/// Where every kmer of the reference sits, kmers holding ambiguity codes are left out
+fn anchor_index(reference: &[u8]) -> HashMap<&[u8], Vec<usize>> {
+    let mut ret: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (i, kmer) in reference.windows(ANCHOR_K).enumerate() {
+        if !kmer.iter().any(|b| bases::is_ambiguous(*b)) {
+            ret.entry(kmer).or_default().push(i);
+        }
+    }
+    ret
+}
+
+
+/// The diagonal, reference position minus fragment position, most anchors of the fragment agree on
+///
+/// Anchors are grouped by band first, since indels shift the exact diagonal, then the group's most
+/// common exact diagonal is returned with the size of the group.
+fn best_diagonal(fragment: &[u8], index: &HashMap<&[u8], Vec<usize>>) -> Option<(i64, u32)> {
+    let mut diagonals: HashMap<i64, u32> = HashMap::new();
+    for (i, kmer) in fragment.windows(ANCHOR_K).enumerate() {
+        match index.get(kmer) {
+            Some(positions) if positions.len() <= MAX_OCCURRENCES => {
+                for &p in positions {
+                    *diagonals.entry(p as i64 - i as i64).or_insert(0) += 1;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let mut bands: HashMap<i64, u32> = HashMap::new();
+    for (diagonal, votes) in &diagonals {
+        *bands.entry(diagonal.div_euclid(BAND as i64)).or_insert(0) += votes;
+    }
+    let (band, votes) = bands.into_iter().max_by_key(|(band, votes)| (*votes, std::cmp::Reverse(*band)))?;
+    if votes < MIN_ANCHORS {
+        return None;
+    }
+    diagonals.into_iter()
+        .filter(|(diagonal, _)| diagonal.div_euclid(BAND as i64) == band)
+        .max_by_key(|(diagonal, votes)| (*votes, std::cmp::Reverse(*diagonal)))
+        .map(|(diagonal, _)| (diagonal, votes))
+}
+
+
+/// Identity of the fragment aligned in full against the reference, within a band around the diagonal
+///
+/// Unit costs, with the reference free to start and end anywhere. Every cell of the band carries the
+/// matches and columns of the path that reached it, so no traceback is needed. Row i holds the cells
+/// of reference positions i + diagonal - BAND to i + diagonal + BAND.
+fn banded_identity(fragment: &[u8], reference: &[u8], diagonal: i64) -> f64 {
+    const WIDTH: usize = 2 * BAND + 1;
+    const EMPTY: (u32, u32, u32) = (u32::MAX, 0, 0); //(cost, matches, columns), outside the reference
+    let position = |i: usize, b: usize| i as i64 + diagonal + b as i64 - BAND as i64; //reference position of a cell
+    let inside = |r: i64| r >= 0 && r <= reference.len() as i64;
+
+    let mut prev: Vec<(u32, u32, u32)> = (0..WIDTH).map(|b| if inside(position(0, b)) { (0, 0, 0) } else { EMPTY }).collect(); //the reference may start anywhere
+    let mut cur = vec![EMPTY; WIDTH];
+    for i in 1..=fragment.len() {
+        for b in 0..WIDTH {
+            let r = position(i, b);
+            cur[b] = EMPTY;
+            if !inside(r) {
+                continue;
+            }
+            if r >= 1 && prev[b].0 != u32::MAX { //fragment base against reference base
+                let same = bases::compatible(fragment[i-1], reference[r as usize - 1]);
+                cur[b] = (prev[b].0 + u32::from(!same), prev[b].1 + u32::from(same), prev[b].2 + 1);
+            }
+            if b + 1 < WIDTH && prev[b+1].0 != u32::MAX && prev[b+1].0 + 1 < cur[b].0 { //fragment base against a gap
+                cur[b] = (prev[b+1].0 + 1, prev[b+1].1, prev[b+1].2 + 1);
+            }
+            if b >= 1 && cur[b-1].0 != u32::MAX && cur[b-1].0 + 1 < cur[b].0 { //reference base against a gap
+                cur[b] = (cur[b-1].0 + 1, cur[b-1].1, cur[b-1].2 + 1);
+            }
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    // the reference may end anywhere
+    prev.iter()
+        .filter(|cell| cell.0 != u32::MAX)
+        .min_by_key(|cell| cell.0)
+        .map(|cell| cell.1 as f64 / cell.2.max(1) as f64)
+        .unwrap_or(0.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_sequence(length: usize) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..length).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect()
+    }
+
+    /// Swap every 50th base for the next one in ACGT
+    fn mutate(sequence: &[u8]) -> Vec<u8> {
+        sequence.iter().enumerate()
+            .map(|(i, b)| if i % 50 == 25 { b"CGTA"[b"ACGT".iter().position(|c| c == b).unwrap()] } else { *b })
+            .collect()
+    }
+
+    #[test]
+    fn a_genome_against_itself() {
+        let reference = random_sequence(2 * FRAGMENT);
+        let estimate = ani(&reference, &reference);
+        assert_eq!((estimate.fragments, estimate.mapped), (2, 2));
+        assert_eq!((estimate.ani, estimate.aligned_fraction), (1.0, 1.0));
+
+        // the other strand maps just as well
+        let reverse: Vec<u8> = reference.iter().rev().map(|b| bases::complement(*b)).collect();
+        assert_eq!(ani(&reverse, &reference).ani, 1.0);
+    }
+
+    #[test]
+    fn a_mutated_copy() {
+        let reference = random_sequence(2 * FRAGMENT);
+        let estimate = ani(&mutate(&reference), &reference);
+        assert_eq!(estimate.mapped, 2);
+        assert!((estimate.ani - 0.98).abs() < 1e-9, "ani is {}", estimate.ani);
+    }
+
+    #[test]
+    fn banded_identity_follows_the_diagonal() {
+        let fragment = random_sequence(200);
+        let mut reference = b"TTTTTTTTTT".to_vec();
+        reference.extend(mutate(&fragment));
+        assert_eq!(banded_identity(&fragment, &fragment, 0), 1.0);
+        assert!((banded_identity(&fragment, &reference, 10) - 0.98).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,74 @@
+/// Bit for each of A, C, G and T, an IUPAC code sets the bits of every base it stands for
+const A: u8 = 1;
+const C: u8 = 2;
+const G: u8 = 4;
+const T: u8 = 8;
+
+
+/// The bases a symbol may stand for, nothing for symbols that aren't nucleotide codes
+///
+/// Lower case soft-masked symbols stand for the same bases as upper case ones, and U is read as T.
+pub fn mask(b: u8) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'A' => A,
+        b'C' => C,
+        b'G' => G,
+        b'T' | b'U' => T,
+        b'R' => A | G,
+        b'Y' => C | T,
+        b'S' => C | G,
+        b'W' => A | T,
+        b'K' => G | T,
+        b'M' => A | C,
+        b'B' => C | G | T,
+        b'D' => A | G | T,
+        b'H' => A | C | T,
+        b'V' => A | C | G,
+        b'N' => A | C | G | T,
+        _ => 0,
+    }
+}
+
+
+/// Bring a symbol read from a genome file to the form every comparison expects
+///
+/// Soft-masked bases are upper cased, U becomes T, and anything that isn't a nucleotide code becomes N.
+pub fn normalize(b: u8) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'U' => b'T',
+        upper if mask(upper) != 0 => upper,
+        _ => b'N',
+    }
+}
+
+
+/// Whether two symbols may stand for the same base, R matches A or G and N matches anything
+pub fn compatible(a: u8, b: u8) -> bool {
+    a == b || mask(a) & mask(b) != 0
+}
+
+
+/// The base on the opposite strand, ambiguity codes map to the code for the complements of their bases
+pub fn complement(b: u8) -> u8 {
+    match b {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other, //S, W and N are their own complements
+    }
+}
+
+
+/// Whether a symbol stands for more than one base
+pub fn is_ambiguous(b: u8) -> bool {
+    mask(b).count_ones() > 1
+}
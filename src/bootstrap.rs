@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{algorithms::{KmerOptions, KmerSelection}, compare::{self, SplitBits}, distance::DistanceModel, errors::PhyloError, metadata::Metadata, spectrum::KmerSimilarity, structs::{PhyloTree, TreeNode, TreeVertex}, log_info};
+
+
+/// Settings for resampling a tree
+#[derive(Debug, Clone)]
+pub struct BootstrapOptions {
+    pub replicates: u32,    // number of trees to rebuild
+    pub seed: u64,          // seed for the insertion orders and every replicate's kmers and descents
+    pub shuffle: bool,      // whether each replicate inserts the genomes in a new order
+    pub model: DistanceModel, // how the replicates measure distances
+    pub kmers: KmerOptions, // how the replicates sample kmers, always at random
+    pub metadata: Metadata, // flags of the genomes
+}
+
+
+/// Rebuild the tree from the same files once per replicate and annotate the reference with support values
+///
+/// Every rebuild samples fresh kmers for each genome, at random even when the reference picked
+/// minimizers, and with shuffle set also inserts the genomes in a different order, since the
+/// incremental builder is sensitive to both. Every replicate draws its own seed from the given one, so
+/// the same seed gives the same replicates.
+///
+/// Only the sampled kmer similarity looks at the resampled kmers, the others compare whole spectra or
+/// sketches that stay the same in every replicate, so they are refused.
+pub fn bootstrap(reference: &mut TreeNode, files: &[String], options: &BootstrapOptions) -> Result<Vec<TreeNode>, PhyloError> {
+    if options.kmers.similarity != KmerSimilarity::Sampled {
+        return Err(PhyloError::SamplingError(String::from("bootstrap resamples kmers, which only the sampled similarity uses")));
+    }
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut replicates = Vec::with_capacity(options.replicates as usize);
+
+    for i in 0..options.replicates {
+        let mut order = files.to_vec();
+        if options.shuffle {
+            order.shuffle(&mut rng);
+        }
+        log_info!("building replicate {} of {}", i + 1, options.replicates);
+        let kmers = KmerOptions { selection: KmerSelection::Random, seed: rng.gen(), ..options.kmers };
+        let (tree, _) = PhyloTree::from_files(&order, options.model, &kmers, &options.metadata)?;
+        replicates.push(tree.root);
+    }
+
+    annotate_support(reference, &replicates);
+    Ok(replicates)
+}
+
+
+/// Set the support of every node in the reference to the fraction of replicates that contain its split
+///
+/// Nodes whose clade is trivial (fewer than two genomes on either side) are left without support.
+pub fn annotate_support(reference: &mut TreeNode, replicates: &[TreeNode]) {
+    let index = compare::leaf_index(reference);
+
+    // count how many replicates contain each split
+    let mut counts: HashMap<SplitBits, u32> = HashMap::new();
+    for replicate in replicates {
+        for split in compare::tree_splits(replicate, &index).into_keys() {
+            *counts.entry(split).or_insert(0) += 1;
+        }
+    }
+
+    let words = index.len().div_ceil(64);
+    annotate_recursive(reference, &index, &counts, replicates.len(), words);
+}
+
+
+/// Internal recursive function that annotates a node and returns its clade
+fn annotate_recursive(node: &mut TreeNode, index: &HashMap<String, usize>, counts: &HashMap<SplitBits, u32>, total: usize, words: usize) -> SplitBits {
+    let mut clade = vec![0u64; words];
+    match &mut node.vertex {
+        TreeVertex::Split(s) => {
+            for child in s {
+                let child_clade = annotate_recursive(child, index, counts, total, words);
+                for (w, c) in clade.iter_mut().zip(child_clade) {
+                    *w |= c;
+                }
+            }
+        },
+        TreeVertex::Floor(f) => {
+            for genome in f {
+                if let Some(i) = index.get(genome.name()) {
+                    clade[i / 64] |= 1 << (i % 64);
+                }
+            }
+        }
+    }
+
+    let size: u32 = clade.iter().map(|w| w.count_ones()).sum();
+    node.support = if size >= 2 && size as usize + 2 <= index.len() && total > 0 {
+        let found = counts.get(&compare::normalize(&clade, index.len())).copied().unwrap_or(0);
+        Some(found as f64 / total as f64)
+    } else {
+        None
+    };
+    clade
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::parse_tree;
+
+    #[test]
+    fn support_is_the_share_of_replicates_with_the_split() {
+        let mut reference = parse_tree("split:\n    floor:\n        A\n        B\n    floor:\n        C\n    floor:\n        D\n        E\n").unwrap();
+        let replicates = [
+            parse_tree("split:\n    floor:\n        B\n        A\n    floor:\n        C\n    floor:\n        E\n        D\n").unwrap(),
+            parse_tree("split:\n    floor:\n        A\n        C\n    floor:\n        B\n    floor:\n        D\n        E\n").unwrap(),
+        ];
+        annotate_support(&mut reference, &replicates);
+
+        let TreeVertex::Split(s) = &reference.vertex else { panic!("the root should be a split") };
+        assert_eq!(reference.support, None); //every genome sits under the root
+        assert_eq!(s[0].support, Some(0.5)); //A and B are only together in the first replicate
+        assert_eq!(s[1].support, None); //a lone genome is a trivial split
+        assert_eq!(s[2].support, Some(1.0));
+    }
+
+    #[test]
+    fn only_sampled_similarity_can_be_resampled() {
+        let mut reference = parse_tree("floor:\n    A\n").unwrap();
+        let options = BootstrapOptions {
+            replicates: 1,
+            seed: 0,
+            shuffle: false,
+            model: DistanceModel::Raw,
+            kmers: KmerOptions { similarity: KmerSimilarity::Sketch, ..KmerOptions::default() },
+            metadata: Metadata::default(),
+        };
+        assert!(bootstrap(&mut reference, &[], &options).is_err());
+    }
+}
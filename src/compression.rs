@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+
+/// Bases hashed to find earlier occurrences of the upcoming sequence
+const SEED: usize = 8;
+
+/// Most recent earlier occurrences of a seed tried as matches
+const MAX_CANDIDATES: usize = 16;
+
+
+/// Size in bits of the sequence compressed by a simple LZ77 coder
+///
+/// The sequence is parsed greedily into literals and back-references to the longest earlier match,
+/// found through the last few occurrences of the next SEED bases. Nothing is written out, every token is
+/// only charged its size: a flag bit, then 2 bits for a base or 8 for any other symbol, or the distance
+/// in log2 of the position plus an Elias gamma coded length. A match is only taken when it's cheaper than
+/// writing its bases out.
+pub fn compressed_bits(sequence: &[u8]) -> f64 {
+    let mut seeds: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut bits = 0.0;
+    let mut i = 0;
+    let mut indexed = 0; //every position before this is in the seed table
+
+    while i < sequence.len() {
+        // longest earlier match starting at i
+        let mut length = 0;
+        if let Some(candidates) = sequence.get(i..i + SEED).and_then(|seed| seeds.get(seed)) {
+            for &start in candidates.iter().rev().take(MAX_CANDIDATES) {
+                length = length.max(sequence[start..].iter().zip(&sequence[i..]).take_while(|(a, b)| a == b).count());
+            }
+        }
+
+        let match_bits = 1.0 + ((i + 1) as f64).log2().ceil() + gamma_bits(length);
+        let literal_bits: f64 = sequence[i..i + length.max(1)].iter().map(|b| literal(*b)).sum();
+        let step = if length > 0 && match_bits < literal_bits {
+            bits += match_bits;
+            length
+        } else {
+            bits += literal(sequence[i]);
+            1
+        };
+
+        // make the bases just covered available to later matches
+        i += step;
+        while indexed < i && indexed + SEED <= sequence.len() {
+            seeds.entry(&sequence[indexed..indexed + SEED]).or_default().push(indexed);
+            indexed += 1;
+        }
+    }
+    bits
+}
+
+
+/// Normalized compression distance, how much of either sequence the other one leaves to be encoded
+///
+/// Close to 0 for identical sequences and close to 1 for unrelated ones. Unlike an alignment, a
+/// compressor finds shared stretches wherever they are, so rearranged genomes still look close.
+pub fn ncd(first: &[u8], second: &[u8]) -> f64 {
+    let (alone_first, alone_second) = (compressed_bits(first), compressed_bits(second));
+    let together = compressed_bits(&[first, second].concat());
+    let largest = alone_first.max(alone_second);
+    if largest == 0.0 { 0.0 } else { ((together - alone_first.min(alone_second)) / largest).max(0.0) }
+}
+
+
+/// Fraction of the shorter sequence the longer one leaves to be encoded, for genomes that are only partly assembled
+pub fn contained_ncd(first: &[u8], second: &[u8]) -> f64 {
+    let (shorter, longer) = if first.len() <= second.len() { (first, second) } else { (second, first) };
+    let alone = compressed_bits(shorter);
+    let extra = compressed_bits(&[longer, shorter].concat()) - compressed_bits(longer);
+    if alone == 0.0 { 0.0 } else { (extra / alone).clamp(0.0, 1.0) }
+}
+
+
+/// Bits taken by a literal: a flag, then a base or a whole byte
+fn literal(b: u8) -> f64 {
+    if matches!(b, b'A' | b'C' | b'G' | b'T') { 3.0 } else { 9.0 }
+}
+
+
+/// Bits taken by a number in Elias gamma code
+fn gamma_bits(n: usize) -> f64 {
+    2.0 * (n.max(1) as f64).log2().floor() + 1.0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_sequence(length: usize, seed: u64) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..length).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect()
+    }
+
+    #[test]
+    fn repeats_compress() {
+        let random = compressed_bits(&random_sequence(1000, 0));
+        assert!(compressed_bits(&b"ACGT".repeat(250)) < random / 10.0);
+        assert_eq!(compressed_bits(b""), 0.0);
+    }
+
+    #[test]
+    fn identical_and_unrelated_sequences() {
+        let (first, second) = (random_sequence(2000, 0), random_sequence(2000, 1));
+        assert!(ncd(&first, &first) < 0.05, "{}", ncd(&first, &first));
+        assert!(ncd(&first, &second) > 0.9, "{}", ncd(&first, &second));
+        assert_eq!(ncd(b"", b""), 0.0);
+
+        // swapping the halves of a genome barely changes it
+        let swapped = [&first[1000..], &first[..1000]].concat();
+        assert!(ncd(&first, &swapped) < 0.05, "{}", ncd(&first, &swapped));
+    }
+
+    #[test]
+    fn contained_sequences() {
+        let (first, second) = (random_sequence(2000, 0), random_sequence(500, 1));
+        assert!(contained_ncd(&first[500..1000], &first) < 0.05);
+        assert!(contained_ncd(&first, &second) > 0.9);
+    }
+}
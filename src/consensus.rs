@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::{compare::{self, SplitBits}, errors::PhyloError, structs::{Genome, TreeNode, TreeVertex}};
+
+
+/// Which splits make it into a consensus tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsensusRule {
+    Strict,         // splits found in every tree
+    Majority,       // splits found in more than half of the trees
+}
+impl ConsensusRule {
+
+    /// Parse a rule from its name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "strict" => Some(ConsensusRule::Strict),
+            "majority" => Some(ConsensusRule::Majority),
+            _ => None,
+        }
+    }
+
+    /// Check whether a split found in the given number of trees is kept
+    fn keeps(&self, found: usize, total: usize) -> bool {
+        match self {
+            ConsensusRule::Strict => found == total,
+            ConsensusRule::Majority => found * 2 > total,
+        }
+    }
+}
+
+
+/// Build a consensus tree out of trees over the same set of genomes
+///
+/// Splits are counted as unrooted bipartitions, and every node of the result carries the fraction of
+/// trees containing its split as its support. The result is rooted on the side of the first genome by
+/// name, and genomes whose position is unresolved hang directly off the lowest clade that holds them.
+pub fn consensus(trees: &[TreeNode], rule: ConsensusRule) -> Result<TreeNode, PhyloError> {
+    let first = trees.first().ok_or(PhyloError::ConsensusError(String::from("no trees were given")))?;
+    let index = compare::leaf_index(first);
+
+    // every tree needs to hold exactly the same genomes
+    for tree in &trees[1..] {
+        let names = compare::leaf_index(tree);
+        if names.len() != index.len() || names.keys().any(|n| !index.contains_key(n)) {
+            return Err(PhyloError::ConsensusError(String::from("trees don't hold the same set of genomes")));
+        }
+    }
+
+    // count the splits
+    let mut counts: HashMap<SplitBits, usize> = HashMap::new();
+    for tree in trees {
+        for split in compare::tree_splits(tree, &index).into_keys() {
+            *counts.entry(split).or_insert(0) += 1;
+        }
+    }
+
+    // keep the splits the rule accepts, largest clades first so parents come before their children
+    let mut kept: Vec<(SplitBits, f64)> = counts.into_iter()
+        .filter(|(_, found)| rule.keeps(*found, trees.len()))
+        .map(|(split, found)| (split, found as f64 / trees.len() as f64))
+        .collect();
+    kept.sort_by_key(|(split, _)| std::cmp::Reverse(split.iter().map(|w| w.count_ones()).sum::<u32>()));
+
+    // collect the genomes so they can be placed in the new tree
+    let mut genomes: Vec<Option<Genome>> = vec![None; index.len()];
+    collect_genomes(first, &index, &mut genomes);
+    let genomes: Vec<Genome> = genomes.into_iter().map(|g| g.unwrap()).collect();
+
+    // every kept split is a clade within the tree rooted next to genome 0
+    let words = index.len().div_ceil(64);
+    let mut everything = vec![u64::MAX; words];
+    if !index.len().is_multiple_of(64) {
+        everything[words - 1] = (1u64 << (index.len() % 64)) - 1;
+    }
+    let mut root = build_node(&everything, None, &kept, &genomes);
+    root.renumber(0)?;
+    Ok(root)
+}
+
+
+/// Internal recursive function that builds the node for a clade out of the largest kept clades inside it
+fn build_node(clade: &SplitBits, support: Option<f64>, kept: &[(SplitBits, f64)], genomes: &[Genome]) -> TreeNode {
+    // find the maximal kept clades strictly inside this one, the list is sorted largest first
+    let mut children: Vec<&(SplitBits, f64)> = Vec::new();
+    for candidate in kept {
+        let inside = candidate.0.iter().zip(clade).all(|(c, p)| c & !p == 0) && candidate.0 != *clade;
+        let covered = children.iter().any(|child| candidate.0.iter().zip(&child.0).all(|(c, p)| c & !p == 0));
+        if inside && !covered {
+            children.push(candidate);
+        }
+    }
+
+    // genomes of this clade that aren't in any child clade
+    let mut loose = clade.clone();
+    for child in &children {
+        for (l, c) in loose.iter_mut().zip(&child.0) {
+            *l &= !c;
+        }
+    }
+    let loose: Vec<Genome> = genomes.iter().enumerate()
+        .filter(|(i, _)| loose[i / 64] & (1 << (i % 64)) != 0)
+        .map(|(_, g)| g.clone())
+        .collect();
+
+    let mut node = TreeNode::new_with_floor(0, 0);
+    node.support = support;
+    if children.is_empty() { //nothing resolved inside this clade, so it's a floor
+        node.vertex = TreeVertex::Floor(loose);
+    } else { //each child clade becomes a branch, and each loose genome a branch of its own
+        node.vertex = TreeVertex::new_split();
+        for child in children {
+            node.vertex.push_node(build_node(&child.0, Some(child.1), kept, genomes));
+        }
+        for genome in loose {
+            let mut leaf = TreeNode::new_with_floor(0, 0);
+            leaf.vertex = TreeVertex::Floor(vec![genome]);
+            node.vertex.push_node(leaf);
+        }
+    }
+    node
+}
+
+
+/// Internal recursive function that finds the genome for every bit position
+fn collect_genomes(node: &TreeNode, index: &HashMap<String, usize>, genomes: &mut [Option<Genome>]) {
+    match &node.vertex {
+        TreeVertex::Split(s) => {
+            for child in s {
+                collect_genomes(child, index, genomes);
+            }
+        },
+        TreeVertex::Floor(f) => {
+            for genome in f {
+                if let Some(i) = index.get(genome.name()) {
+                    genomes[*i] = Some(genome.clone());
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::input::parse_tree;
+
+    fn trees() -> Vec<TreeNode> {
+        [
+            "split:\n    floor:\n        A\n        B\n    floor:\n        C\n    floor:\n        D\n        E\n",
+            "split:\n    split:\n        floor:\n            A\n            B\n        floor:\n            C\n    floor:\n        D\n        E\n",
+            "split:\n    floor:\n        A\n        C\n    floor:\n        B\n    floor:\n        D\n        E\n",
+        ].iter().map(|t| parse_tree(t).unwrap()).collect()
+    }
+
+    /// The splits of a tree, as bitsets of the side without A over A, B, C, D and E
+    fn splits(tree: &TreeNode) -> HashSet<SplitBits> {
+        compare::tree_splits(tree, &compare::leaf_index(tree)).into_keys().collect()
+    }
+
+    #[test]
+    fn majority_keeps_splits_in_most_trees() {
+        // AB|CDE is in two of the three trees, AC|BDE in one, DE|ABC in all of them
+        let tree = consensus(&trees(), ConsensusRule::Majority).unwrap();
+        assert_eq!(splits(&tree), HashSet::from([vec![0b11100], vec![0b11000]]));
+        assert_eq!(compare::leaf_names(&tree).len(), 5);
+    }
+
+    #[test]
+    fn strict_keeps_splits_in_every_tree() {
+        let tree = consensus(&trees(), ConsensusRule::Strict).unwrap();
+        assert_eq!(splits(&tree), HashSet::from([vec![0b11000]]));
+    }
+
+    #[test]
+    fn trees_need_the_same_genomes() {
+        let mut different = trees();
+        different.push(parse_tree("split:\n    floor:\n        A\n        B\n    floor:\n        C\n        F\n").unwrap());
+        assert!(consensus(&different, ConsensusRule::Majority).is_err());
+        assert!(consensus(&[], ConsensusRule::Majority).is_err());
+    }
+}
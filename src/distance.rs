@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+
+use crate::{algorithms, ani, bases, compression, alignment::{self, AlignOp, Alignment, Scoring}, metadata::GenomeFlags, segments::Segment};
+
+
+/// Corrected distances are capped at this many substitutions per site once the correction breaks down
+pub const SATURATED: f64 = 10.0;
+
+
+/// Length of the kmers used as anchors when lining up circular sequences
+const ANCHOR_K: usize = 12;
+
+
+/// How two sequences are lined up before their distance is measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Comparison {
+    pub local: bool,        // only the best matching region counts, for partial genomes
+    pub circular: bool,     // the second sequence is rotated to line up with the first
+}
+impl Comparison {
+
+    /// How two genomes with the given flags should be compared
+    pub fn between(first: GenomeFlags, second: GenomeFlags) -> Self {
+        Comparison {
+            local: first.partial || second.partial,
+            circular: first.circular || second.circular,
+        }
+    }
+}
+
+
+/// How the distance between two genomes is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceModel {
+    Raw,            // Levenshtein edit count
+    PDistance,      // differences, gaps included, over the length of the alignment
+    JukesCantor,    // Jukes-Cantor correction of the substitution proportion
+    Kimura,         // Kimura two-parameter correction, transitions and transversions apart
+    TamuraNei,      // Tamura-Nei correction, also accounting for base composition
+    Ani,            // one minus the average nucleotide identity, scaled by the aligned fraction
+    Ncd,            // normalized compression distance, alignment-free
+}
+impl DistanceModel {
+
+    /// Parse a model from its name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "raw" | "levenshtein" => Some(DistanceModel::Raw),
+            "p" | "p-distance" => Some(DistanceModel::PDistance),
+            "jc" | "jukes-cantor" => Some(DistanceModel::JukesCantor),
+            "k2p" | "kimura" => Some(DistanceModel::Kimura),
+            "tn93" | "tamura-nei" => Some(DistanceModel::TamuraNei),
+            "ani" => Some(DistanceModel::Ani),
+            "ncd" | "compression" => Some(DistanceModel::Ncd),
+            _ => None,
+        }
+    }
+
+    /// The distance between two sequences under this model
+    pub fn distance(&self, first: &[u8], second: &[u8]) -> f64 {
+        if *self == DistanceModel::Raw {
+            return algorithms::levenshtein(first, second) as f64;
+        }
+        if *self == DistanceModel::Ani {
+            return ani_distance(first, second);
+        }
+        if *self == DistanceModel::Ncd {
+            return compression::ncd(first, second);
+        }
+        let counts = align_counts(first, second);
+        self.correct(&counts, &base_frequencies(&[first, second]))
+    }
+
+    /// The distance between two sequences, lined up as the comparison asks first
+    pub fn compare(&self, first: &[u8], second: &[u8], comparison: Comparison) -> f64 {
+        let rotated;
+        let second = if comparison.circular {
+            rotated = rotate(second, best_rotation(first, second));
+            &rotated
+        } else {
+            second
+        };
+        if comparison.local { self.local_distance(first, second) } else { self.distance(first, second) }
+    }
+
+    /// The distance between two genomes made of segments, along with how each segment of the first was matched
+    ///
+    /// Segments are paired off one to one, closest pair first, so the order segments are listed in doesn't
+    /// matter and no segment stands in for two. Every pair is compared shorter sequence first, and the
+    /// pairing is made from both sides with ties going to the earlier segments, so swapping the genomes
+    /// gives the same distance. Segments left without a partner are charged as if nothing matched them.
+    /// Raw distances are summed over the segments of both genomes, the others are averaged weighted by
+    /// segment length.
+    pub fn segmented_distance(&self, first: &[Segment], second: &[Segment], comparison: Comparison) -> (f64, Vec<SegmentMatch>) {
+        let measure = |a: &[u8], b: &[u8]| match (a.len(), a) <= (b.len(), b) {
+            true => self.compare(a, b, comparison),
+            false => self.compare(b, a, comparison),
+        };
+        let table: Vec<Vec<f64>> = first.iter().map(|f| second.iter().map(|s| measure(&f.sequence, &s.sequence)).collect()).collect();
+        let transposed: Vec<Vec<f64>> = (0..second.len()).map(|j| table.iter().map(|row| row[j]).collect()).collect();
+
+        let partners = pair_off(&table);
+        let distance = (self.paired_distance(first, second, &table, &partners) + self.paired_distance(second, first, &transposed, &pair_off(&transposed))) / 2.0;
+        let matches = first.iter().zip(&partners).enumerate().map(|(i, (segment, partner))| match partner {
+            Some(j) => SegmentMatch { segment: segment.name.clone(), matched: second[*j].name.clone(), distance: table[i][*j] },
+            None => SegmentMatch { segment: segment.name.clone(), matched: String::new(), distance: self.unmatched(segment) },
+        }).collect();
+        (distance, matches)
+    }
+
+    /// Distance between two genomes given the partner of every segment of the first, unpaired segments are charged in full
+    fn paired_distance(&self, first: &[Segment], second: &[Segment], table: &[Vec<f64>], partners: &[Option<usize>]) -> f64 {
+        let mut paired = vec![false; second.len()];
+        let mut charges = Vec::with_capacity(first.len() + second.len()); //(distance, bases it covers)
+        for (i, partner) in partners.iter().enumerate() {
+            match partner {
+                Some(j) => {
+                    paired[*j] = true;
+                    charges.push((table[i][*j], first[i].sequence.len() + second[*j].sequence.len()));
+                },
+                None => charges.push((self.unmatched(&first[i]), first[i].sequence.len())),
+            }
+        }
+        charges.extend(second.iter().zip(paired).filter(|(_, p)| !p).map(|(s, _)| (self.unmatched(s), s.sequence.len())));
+
+        let total: usize = charges.iter().map(|c| c.1).sum();
+        if *self == DistanceModel::Raw || total == 0 {
+            charges.iter().map(|c| c.0).sum()
+        } else {
+            charges.iter().map(|(d, bases)| d * *bases as f64).sum::<f64>() / total as f64
+        }
+    }
+
+    /// What a segment without a partner in the other genome is charged, its distance from an empty sequence
+    fn unmatched(&self, segment: &Segment) -> f64 {
+        match self {
+            DistanceModel::Raw => segment.sequence.len() as f64, //every base is an insertion
+            DistanceModel::PDistance | DistanceModel::Ani | DistanceModel::Ncd => 1.0,
+            DistanceModel::JukesCantor | DistanceModel::Kimura | DistanceModel::TamuraNei => SATURATED,
+        }
+    }
+
+    /// The distance over the best matching region of two sequences, for genomes that are only partly assembled
+    ///
+    /// Differences are counted on a local alignment, so the flanks of the longer sequence are free. Whatever
+    /// of the shorter sequence falls outside the region is still charged as gaps, otherwise two unrelated
+    /// genomes would look close over the few bases they happen to share.
+    ///
+    /// Under the ANI model only the identity of the mapped fragments of the shorter sequence counts, and
+    /// under NCD only what the longer sequence leaves of the shorter one to be encoded.
+    pub fn local_distance(&self, first: &[u8], second: &[u8]) -> f64 {
+        if *self == DistanceModel::Ani {
+            let (shorter, longer) = if first.len() <= second.len() { (first, second) } else { (second, first) };
+            let estimate = ani::ani(shorter, longer);
+            return if estimate.mapped == 0 { 1.0 } else { 1.0 - estimate.ani };
+        }
+        if *self == DistanceModel::Ncd {
+            return compression::contained_ncd(first, second);
+        }
+        let local = alignment::local_align(first, second, &Scoring::default(), true);
+
+        let mut counts = AlignmentCounts::from_alignment(&local.alignment, &first[local.reference_start..], &second[local.query_start..]);
+        let (covered, shorter) = if first.len() <= second.len() {
+            (local.reference_end() - local.reference_start, first.len())
+        } else {
+            (local.query_end() - local.query_start, second.len())
+        };
+        counts.gaps += (shorter - covered) as u32;
+        self.correct(&counts, &base_frequencies(&[first, second]))
+    }
+
+    /// Turn the counts of an alignment into a distance, base frequencies are only used by Tamura-Nei
+    pub fn correct(&self, counts: &AlignmentCounts, frequencies: &[f64; 4]) -> f64 {
+        let sites = counts.sites() as f64;
+        if *self == DistanceModel::Raw {
+            return counts.differences() as f64;
+        }
+        if *self == DistanceModel::PDistance {
+            let columns = counts.columns();
+            return if columns == 0 { 0.0 } else { counts.differences() as f64 / columns as f64 };
+        }
+        if sites == 0.0 { //nothing could be compared
+            return if counts.columns() == 0 { 0.0 } else { SATURATED };
+        }
+
+        let p1 = counts.purine_transitions as f64 / sites;
+        let p2 = counts.pyrimidine_transitions as f64 / sites;
+        let q = counts.transversions as f64 / sites;
+        let d = match self {
+            DistanceModel::JukesCantor => -0.75 * log(1.0 - 4.0 / 3.0 * (p1 + p2 + q)),
+            DistanceModel::TamuraNei if frequencies.iter().all(|f| *f > 0.0) => tamura_nei(p1, p2, q, frequencies),
+            _ => -0.5 * log(1.0 - 2.0 * (p1 + p2) - q) - 0.25 * log(1.0 - 2.0 * q), //Kimura, and Tamura-Nei when a base is missing
+        };
+        if !d.is_finite() {
+            SATURATED
+        } else if d > 0.0 {
+            d.min(SATURATED)
+        } else {
+            0.0 //identical sequences give -0.0
+        }
+    }
+}
+
+
+/// One minus the identity of two sequences times the fraction of them that aligned, averaged over both directions
+///
+/// ANI alone only looks at the fragments that mapped, so two genomes sharing a single gene would look
+/// as close as two copies of the same genome.
+pub fn ani_distance(first: &[u8], second: &[u8]) -> f64 {
+    let one_way = |query: &[u8], reference: &[u8]| {
+        let estimate = ani::ani(query, reference);
+        1.0 - estimate.ani * estimate.aligned_fraction
+    };
+    (one_way(first, second) + one_way(second, first)) / 2.0
+}
+
+
+/// Offset that best lines a circular sequence up with another, found by voting over shared kmers
+///
+/// Every kmer of the second sequence, read around the circle, votes for the offset that would put it
+/// where the same kmer sits in the first. Kmers repeated in the first sequence don't vote, since they
+/// can't tell offsets apart. Without a single shared kmer the sequence is left as it is.
+pub fn best_rotation(first: &[u8], second: &[u8]) -> usize {
+    let n = second.len();
+    if first.len() < ANCHOR_K || n < ANCHOR_K {
+        return 0;
+    }
+
+    // where every kmer of the first sequence sits, none for repeats
+    let mut anchors: HashMap<&[u8], Option<usize>> = HashMap::new();
+    for (i, kmer) in first.windows(ANCHOR_K).enumerate() {
+        anchors.entry(kmer).and_modify(|a| *a = None).or_insert(Some(i));
+    }
+
+    let circle: Vec<u8> = second.iter().chain(&second[..ANCHOR_K - 1]).copied().collect();
+    let mut votes = vec![0u32; n];
+    for (j, kmer) in circle.windows(ANCHOR_K).enumerate() {
+        if let Some(Some(i)) = anchors.get(kmer) {
+            votes[(j + n - i % n) % n] += 1;
+        }
+    }
+    let best = votes.iter().enumerate().max_by_key(|(offset, v)| (**v, std::cmp::Reverse(*offset))).map(|(offset, _)| offset).unwrap_or(0);
+    if votes[best] == 0 { 0 } else { best }
+}
+
+
+/// The sequence read around the circle starting at the offset
+pub fn rotate(sequence: &[u8], offset: usize) -> Vec<u8> {
+    let offset = if sequence.is_empty() { 0 } else { offset % sequence.len() };
+    sequence[offset..].iter().chain(&sequence[..offset]).copied().collect()
+}
+
+
+/// The segment of another genome paired with one segment
+#[derive(Debug, Clone)]
+pub struct SegmentMatch {
+    pub segment: String,    // name of the segment
+    pub matched: String,    // name of its partner in the other genome, empty when it has none
+    pub distance: f64,      // distance between the two, or the charge for having no partner
+}
+
+
+/// Pair rows with columns one to one, closest pair first, returns the column paired with every row
+///
+/// Ties go to the earlier row, then the earlier column. Rows left over once the columns run out get none.
+pub fn pair_off(table: &[Vec<f64>]) -> Vec<Option<usize>> {
+    let mut pairs: Vec<(usize, usize)> = table.iter().enumerate().flat_map(|(i, row)| (0..row.len()).map(move |j| (i, j))).collect();
+    pairs.sort_by(|a, b| table[a.0][a.1].total_cmp(&table[b.0][b.1]).then(a.cmp(b)));
+
+    let mut ret = vec![None; table.len()];
+    let mut taken = vec![false; table.first().map(|r| r.len()).unwrap_or(0)];
+    for (i, j) in pairs {
+        if ret[i].is_none() && !taken[j] {
+            ret[i] = Some(j);
+            taken[j] = true;
+        }
+    }
+    ret
+}
+
+
+/// Tamura-Nei distance from the proportions of purine transitions, pyrimidine transitions and transversions
+fn tamura_nei(p1: f64, p2: f64, q: f64, frequencies: &[f64; 4]) -> f64 {
+    let [a, c, g, t] = *frequencies;
+    let r = a + g;
+    let y = c + t;
+    -2.0 * a * g / r * log(1.0 - r / (2.0 * a * g) * p1 - q / (2.0 * r))
+        - 2.0 * c * t / y * log(1.0 - y / (2.0 * c * t) * p2 - q / (2.0 * y))
+        - 2.0 * (r * y - a * g * y / r - c * t * r / y) * log(1.0 - q / (2.0 * r * y))
+}
+
+
+/// Natural log that reports a saturated correction as infinity instead of NaN
+fn log(x: f64) -> f64 {
+    if x > 0.0 { x.ln() } else { f64::NEG_INFINITY }
+}
+
+
+/// What the columns of an alignment hold
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlignmentCounts {
+    pub matches: u32,                   // identical bases, or symbols that may stand for the same base
+    pub purine_transitions: u32,        // A against G
+    pub pyrimidine_transitions: u32,    // C against T
+    pub transversions: u32,             // a purine against a pyrimidine
+    pub other_mismatches: u32,          // mismatches involving anything other than A, C, G and T
+    pub gaps: u32,                      // columns with a gap in either sequence
+}
+impl AlignmentCounts {
+
+    /// Columns where both sequences hold one of A, C, G and T
+    pub fn sites(&self) -> u32 {
+        self.matches + self.purine_transitions + self.pyrimidine_transitions + self.transversions
+    }
+
+    /// Columns that differ, which is the edit distance of the alignment
+    pub fn differences(&self) -> u32 {
+        self.purine_transitions + self.pyrimidine_transitions + self.transversions + self.other_mismatches + self.gaps
+    }
+
+    /// Every column of the alignment
+    pub fn columns(&self) -> u32 {
+        self.matches + self.differences()
+    }
+
+    /// Count the columns of an alignment between the two sequences
+    pub fn from_alignment(alignment: &Alignment, reference: &[u8], query: &[u8]) -> Self {
+        let mut ret = AlignmentCounts::default();
+        let (mut r, mut q) = (0, 0);
+        for op in &alignment.ops {
+            match op {
+                AlignOp::Match | AlignOp::Mismatch => {
+                    ret.add_pair(reference[r], query[q]);
+                    r += 1;
+                    q += 1;
+                },
+                AlignOp::Insertion => {
+                    ret.gaps += 1;
+                    q += 1;
+                },
+                AlignOp::Deletion => {
+                    ret.gaps += 1;
+                    r += 1;
+                },
+            }
+        }
+        ret
+    }
+
+    /// Add one aligned pair of symbols
+    fn add_pair(&mut self, a: u8, b: u8) {
+        let (a, b) = (a.to_ascii_uppercase(), b.to_ascii_uppercase());
+        if bases::compatible(a, b) {
+            self.matches += 1;
+            return;
+        }
+        match (base_class(a), base_class(b)) {
+            (Some(x), Some(y)) if x == y && x == Base::Purine => self.purine_transitions += 1,
+            (Some(x), Some(y)) if x == y => self.pyrimidine_transitions += 1,
+            (Some(_), Some(_)) => self.transversions += 1,
+            _ => self.other_mismatches += 1,
+        }
+    }
+}
+
+
+/// Purine or pyrimidine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base {
+    Purine,
+    Pyrimidine,
+}
+
+
+/// Whether a symbol is a purine, a pyrimidine, or neither
+fn base_class(b: u8) -> Option<Base> {
+    match b {
+        b'A' | b'G' => Some(Base::Purine),
+        b'C' | b'T' | b'U' => Some(Base::Pyrimidine),
+        _ => None,
+    }
+}
+
+
+/// Frequencies of A, C, G and T over the given sequences
+pub fn base_frequencies(sequences: &[&[u8]]) -> [f64; 4] {
+    let mut counts = [0u64; 4];
+    for sequence in sequences {
+        for b in sequence.iter() {
+            match b.to_ascii_uppercase() {
+                b'A' => counts[0] += 1,
+                b'C' => counts[1] += 1,
+                b'G' => counts[2] += 1,
+                b'T' | b'U' => counts[3] += 1,
+                _ => {}
+            }
+        }
+    }
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return [0.25; 4];
+    }
+    counts.map(|c| c as f64 / total as f64)
+}
+
+
+/// Count the column types of an optimal edit distance alignment of two sequences
+///
+/// Each cell of the dynamic programming table carries the counts of the path that reached it, so the
+/// traceback is folded into the forward pass and only two rows are kept. Ties prefer a match or
+/// substitution over a gap.
+pub fn align_counts(first: &[u8], second: &[u8]) -> AlignmentCounts {
+    let (long, short) = if first.len() >= second.len() { (first, second) } else { (second, first) };
+
+    // (cost, counts) for every prefix of the short sequence
+    let mut prev: Vec<(u32, AlignmentCounts)> = Vec::with_capacity(short.len() + 1);
+    for i in 0..=short.len() {
+        prev.push((i as u32, AlignmentCounts { gaps: i as u32, ..Default::default() }));
+    }
+    let mut cur = prev.clone();
+
+    for (y, &lb) in long.iter().enumerate() {
+        cur[0] = ((y + 1) as u32, AlignmentCounts { gaps: (y + 1) as u32, ..Default::default() });
+        for (x, &sb) in short.iter().enumerate() {
+            let diagonal = prev[x].0 + u32::from(!bases::compatible(lb, sb));
+            let up = prev[x+1].0 + 1;
+            let left = cur[x].0 + 1;
+
+            cur[x+1] = if diagonal <= up && diagonal <= left {
+                let mut counts = prev[x].1;
+                counts.add_pair(lb, sb);
+                (diagonal, counts)
+            } else if up <= left {
+                let mut counts = prev[x+1].1;
+                counts.gaps += 1;
+                (up, counts)
+            } else {
+                let mut counts = cur[x].1;
+                counts.gaps += 1;
+                (left, counts)
+            };
+        }
+        std::mem::swap(&mut cur, &mut prev);
+    }
+    prev[short.len()].1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(name: &str, sequence: &str) -> Segment {
+        Segment { name: String::from(name), sequence: sequence.as_bytes().to_vec() }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn raw_is_the_edit_distance() {
+        let raw = DistanceModel::Raw;
+        assert_eq!(raw.distance(b"GATTACA", b"GTTTACAA"), 2.0);
+        assert_eq!(raw.distance(b"TGCATGCA", b"ATGCATGC"), 2.0);
+        assert_eq!(raw.distance(b"AB", b"BA"), 2.0);
+        assert_eq!(raw.distance(b"ACGT", b""), 4.0);
+        assert_eq!(raw.distance(b"GATTACA", b"TACAGAT"), raw.distance(b"TACAGAT", b"GATTACA"));
+    }
+
+    #[test]
+    fn corrected_models() {
+        // one transversion in four sites
+        assert!(close(DistanceModel::PDistance.distance(b"ACGT", b"ACGA"), 0.25));
+        assert!(close(DistanceModel::JukesCantor.distance(b"ACGT", b"ACGA"), -0.75 * (2.0f64 / 3.0).ln()));
+
+        // one transition in four sites, Kimura gives -0.5 ln(1 - 2P)
+        assert!(close(DistanceModel::Kimura.distance(b"ACGT", b"GCGT"), 0.5 * 2.0f64.ln()));
+        assert_eq!(DistanceModel::Kimura.distance(b"ACGT", b"ACGT"), 0.0);
+    }
+
+    #[test]
+    fn saturated_corrections_are_capped() {
+        let counts = AlignmentCounts { matches: 1, transversions: 3, ..Default::default() };
+        assert_eq!(DistanceModel::JukesCantor.correct(&counts, &[0.25; 4]), SATURATED);
+        assert_eq!(DistanceModel::PDistance.correct(&counts, &[0.25; 4]), 0.75);
+    }
+
+    #[test]
+    fn align_counts_sort_differences() {
+        let counts = align_counts(b"ACGTAC", b"GCTTA"); //A/G, C, G/T, T, A, then C against a gap
+        assert_eq!((counts.matches, counts.purine_transitions, counts.pyrimidine_transitions, counts.transversions, counts.gaps), (3, 1, 0, 1, 1));
+        assert_eq!(counts.differences(), 3);
+    }
+
+    #[test]
+    fn segments_keep_their_closest_match() {
+        // listed in a different order, every segment still finds the one it differs from by a base
+        let first = [segment("1", "ACGTACGT"), segment("2", "TTTT")];
+        let second = [segment("x", "TTTA"), segment("y", "ACGTACGA")];
+        let (distance, matches) = DistanceModel::Raw.segmented_distance(&first, &second, Comparison::default());
+        assert_eq!(distance, 2.0);
+        assert_eq!((matches[0].matched.as_str(), matches[1].matched.as_str()), ("y", "x"));
+
+        // the p-distance is weighted by segment length
+        let p = DistanceModel::PDistance.segmented_distance(&first, &second, Comparison::default()).0;
+        assert!(close(p, (8.0 / 8.0 + 4.0 / 4.0) / 12.0));
+    }
+
+    #[test]
+    fn segments_pair_off_one_to_one() {
+        let first = [segment("1", "ACGTACGT"), segment("2", "TTTT")];
+        let second = [segment("1", "ACGTACGA")];
+        let raw = DistanceModel::Raw;
+
+        // the closest pair differs by one base, the segment left over is charged its length
+        let (distance, matches) = raw.segmented_distance(&first, &second, Comparison::default());
+        assert_eq!(distance, 5.0);
+        assert_eq!((matches[0].matched.as_str(), matches[0].distance), ("1", 1.0));
+        assert_eq!((matches[1].matched.as_str(), matches[1].distance), ("", 4.0));
+        assert_eq!(raw.segmented_distance(&second, &first, Comparison::default()).0, distance);
+
+        // under the p-distance the pair covers 16 bases at 1/8 and the unpaired segment 4 at 1
+        let p = DistanceModel::PDistance.segmented_distance(&first, &second, Comparison::default()).0;
+        assert!(close(p, (16.0 / 8.0 + 4.0) / 20.0));
+    }
+
+    #[test]
+    fn pair_off_ties_go_to_earlier_rows() {
+        assert_eq!(pair_off(&[vec![1.0, 0.0], vec![0.0, 5.0]]), vec![Some(1), Some(0)]);
+        assert_eq!(pair_off(&[vec![2.0, 2.0], vec![2.0, 2.0]]), vec![Some(0), Some(1)]);
+        assert_eq!(pair_off(&[vec![1.0], vec![0.5]]), vec![None, Some(0)]);
+    }
+
+    #[test]
+    fn circular_sequences_are_rotated() {
+        let first = b"ACGTTGCAAGCTTCGAGGATCCAT";
+        let second = rotate(first, 7);
+        assert_eq!(rotate(b"ABCDE", 2), b"CDEAB");
+        assert_eq!(best_rotation(first, &second), first.len() - 7);
+        let circular = Comparison { local: false, circular: true };
+        assert_eq!(DistanceModel::Raw.compare(first, &second, circular), 0.0);
+    }
+}
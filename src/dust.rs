@@ -0,0 +1,74 @@
+/// Number of bases in every window the masker scores
+pub const WINDOW: usize = 64;
+
+/// Windows scoring above this are masked, the level dustmasker uses by default
+pub const THRESHOLD: f64 = 2.0;
+
+
+/// Mark the low-complexity stretches of a sequence, such as poly-A tails, microsatellites and runs of N
+///
+/// Every window is scored by how often its triplets repeat: the sum over every triplet of c(c-1)/2, divided
+/// by one less than the number of triplets in the window. Windows scoring above the threshold are masked
+/// whole. Random sequence scores below 1, a dinucleotide repeat around 15.
+pub fn mask(sequence: &[u8]) -> Vec<bool> {
+    let mut masked = vec![false; sequence.len()];
+    if sequence.len() < 4 {
+        return masked;
+    }
+    let codes: Vec<usize> = sequence.windows(3).map(|t| t.iter().fold(0, |code, b| code * 5 + base_index(*b))).collect();
+    let span = WINDOW.min(sequence.len()) - 2; //triplets in a window
+
+    let mut counts = [0u32; 125];
+    let mut repeats: u64 = 0; //sum of c(c-1)/2 over the window
+    let mut filled = 0; //everything before this is already masked
+    for (i, &code) in codes.iter().enumerate() {
+        repeats += counts[code] as u64;
+        counts[code] += 1;
+        if i >= span { //drop the triplet that just left the window
+            let old = codes[i - span];
+            counts[old] -= 1;
+            repeats -= counts[old] as u64;
+        }
+        if i + 1 >= span && repeats as f64 / (span - 1) as f64 > THRESHOLD {
+            let start = (i + 1 - span).max(filled);
+            masked[start..i + 3].fill(true);
+            filled = i + 3;
+        }
+    }
+    masked
+}
+
+
+/// Index of a base among A, C, G and T, anything else shares a fifth index
+fn base_index(b: u8) -> usize {
+    match b {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => 4,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIED: &[u8] = b"GCTAAAGACAATTACATAACATACACGTCAGCACGAAACTTGTTGGCCCAGTGTGAATCGCTTAAGGGTTAAGTAAGTGT";
+
+    #[test]
+    fn varied_sequences_are_kept() {
+        assert!(!mask(VARIED).contains(&true));
+        assert_eq!(mask(b"ACG"), vec![false; 3]);
+    }
+
+    #[test]
+    fn dinucleotide_repeats_are_masked() {
+        let mut sequence = VARIED.to_vec();
+        sequence.extend(b"CA".repeat(60));
+        let masked = mask(&sequence);
+        assert!(masked[VARIED.len()..].iter().all(|m| *m));
+        assert!(!masked[..VARIED.len() / 2].contains(&true));
+    }
+}
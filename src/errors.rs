@@ -14,6 +14,14 @@ pub enum PhyloError {
     GenomeInsertError(String),
     FileDeleteError,
     PathError(String),
+    SamplingError(String),
+    LogConfigError(String),
+    TreeParseError(String),
+    TreeCompareError(String),
+    ConsensusError(String),
+    RefineError(String),
+    RootError(String),
+    JsonParseError(String),
 }
 impl Error for PhyloError {}
 impl Display for PhyloError {
@@ -51,6 +59,30 @@ impl Display for PhyloError {
             },
             Self::PathError(s) => {
                 write!(f, "PathError ({})", s)
+            },
+            Self::SamplingError(s) => {
+                write!(f, "SamplingError ({})", s)
+            },
+            Self::LogConfigError(s) => {
+                write!(f, "LogConfigError ({})", s)
+            },
+            Self::TreeParseError(s) => {
+                write!(f, "TreeParseError ({})", s)
+            },
+            Self::TreeCompareError(s) => {
+                write!(f, "TreeCompareError ({})", s)
+            },
+            Self::ConsensusError(s) => {
+                write!(f, "ConsensusError ({})", s)
+            },
+            Self::RefineError(s) => {
+                write!(f, "RefineError ({})", s)
+            },
+            Self::RootError(s) => {
+                write!(f, "RootError ({})", s)
+            },
+            Self::JsonParseError(s) => {
+                write!(f, "JsonParseError ({})", s)
             }
         }
     }
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, fs};
+
+use crate::{errors::PhyloError, json::JsonValue, sketch::Sketch, spectrum::KmerSpectrum, structs::{Genome, TreeNode, TreeVertex}};
+
+
+/// Read a tree back from a file written by output::output_tree
+///
+/// Only the shape, the genome names, support values and branch lengths are stored in the file, so
+/// genomes are given the path of their file in the genomes directory if it still exists, and no kmers
+/// or distances.
+pub fn read_tree(file_dir: &str) -> Result<TreeNode, PhyloError> {
+    let contents = fs::read_to_string(file_dir).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?;
+    if contents.trim().is_empty() {
+        return Err(PhyloError::TreeParseError(format!("{} is empty", file_dir)));
+    }
+    parse_tree(&contents)
+}
+
+
+/// Parse a tree in the format written by output::output_tree, see read_tree
+pub fn parse_tree(contents: &str) -> Result<TreeNode, PhyloError> {
+
+    // pair every non-empty line with its indentation level
+    let lines: Vec<(usize, &str)> = contents.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| ((l.len() - l.trim_start().len()) / 4, l.trim()))
+        .collect();
+    if lines.is_empty() {
+        return Err(PhyloError::TreeParseError(String::from("the tree is empty")));
+    }
+
+    let mut next_id = 0;
+    let mut pos = 0;
+    let mut root = read_node(&lines, &mut pos, &mut next_id)?;
+    if pos != lines.len() {
+        return Err(PhyloError::TreeParseError(format!("unexpected line after the root node: {}", lines[pos].1)));
+    }
+    root.renumber(0)?; //fill in the genome paths
+    Ok(root)
+}
+
+
+/// Read the distance of every genome to its closest relative back from a report written by output::output_report
+///
+/// The tree file doesn't keep these, but they decide how a genome is placed next to an existing one. The
+/// report lists every placement in insertion order, so replaying them the way PhyloTree::push updates
+/// closest distances gives back the distances the tree ended with. Genomes missing from the report are
+/// left out, and placed as if their closest distance were unknown.
+pub fn read_closest_distances(file_dir: &str) -> Result<HashMap<String, f64>, PhyloError> {
+    let contents = fs::read_to_string(file_dir).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?;
+    let report = JsonValue::parse(&contents)?;
+    let records = report.as_array().ok_or(PhyloError::JsonParseError(format!("{} does not hold a list of placements", file_dir)))?;
+
+    let mut ret: HashMap<String, f64> = HashMap::new();
+    for record in records {
+        let genome = record.get("genome").and_then(JsonValue::as_str)
+            .ok_or(PhyloError::JsonParseError(format!("placement without a genome name in {}", file_dir)))?;
+        let Some(closest) = record.get("closest").and_then(JsonValue::as_str) else { //the first genome has no relative
+            ret.insert(String::from(genome), f64::INFINITY);
+            continue;
+        };
+        let distance = record.get("distances").and_then(JsonValue::as_array).unwrap_or_default().iter()
+            .find(|d| d.get("genome").and_then(JsonValue::as_str) == Some(closest))
+            .and_then(|d| d.get("distance")).and_then(JsonValue::as_f64)
+            .unwrap_or(f64::INFINITY); //infinities are written as null
+        ret.insert(String::from(genome), distance);
+
+        // the closest relative takes the new distance if it pairs up with the genome, or if it's closer than its own
+        let relative = ret.entry(String::from(closest)).or_insert(f64::INFINITY);
+        match record.get("case").and_then(JsonValue::as_str) {
+            Some("1") => *relative = distance,
+            Some("1.1") | Some("3") => *relative = relative.min(distance),
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+
+/// Internal recursive function that reads the node starting at the given line
+fn read_node(lines: &[(usize, &str)], pos: &mut usize, next_id: &mut u8) -> Result<TreeNode, PhyloError> {
+    let (tabs, kind) = lines[*pos];
+    *pos += 1;
+    let mut node = TreeNode::new_with_floor(*next_id, 0);
+    *next_id = next_id.checked_add(1).ok_or(PhyloError::TreeParseError(String::from("too many nodes to fit in a tree")))?;
+
+    // a node may carry a support value and a branch length after its type
+    let mut tokens = kind.split_whitespace();
+    let kind = tokens.next().unwrap_or_default();
+    for token in tokens {
+        match token.strip_prefix("length=") {
+            Some(length) => node.length = Some(parse_number(length, "branch length")?),
+            None => node.support = Some(parse_number(token, "support value")?),
+        }
+    }
+
+    match kind {
+        "split:" => {
+            node.vertex = TreeVertex::new_split();
+            while *pos < lines.len() && lines[*pos].0 == tabs + 1 { //every child sits one level deeper
+                let child = read_node(lines, pos, next_id)?;
+                node.count += child.count;
+                node.vertex.push_node(child);
+            }
+        },
+        "floor:" => {
+            let mut genomes = Vec::new();
+            while *pos < lines.len() && lines[*pos].0 == tabs + 1 {
+                let (name, length) = match lines[*pos].1.split_once(" length=") {
+                    Some((name, length)) => (name, Some(parse_number(length, "branch length")?)),
+                    None => (lines[*pos].1, None),
+                };
+                let mut genome = genome_from_name(name);
+                genome.length = length;
+                genomes.push(genome);
+                *pos += 1;
+            }
+            node.count = genomes.len() as u32;
+            node.vertex = TreeVertex::Floor(genomes);
+        },
+        other => {
+            return Err(PhyloError::TreeParseError(format!("expected 'split:' or 'floor:', found '{}'", other)));
+        }
+    }
+    Ok(node)
+}
+
+
+/// Parse a number found in a tree file, naming what it was in the error
+fn parse_number(s: &str, what: &str) -> Result<f64, PhyloError> {
+    s.trim().parse::<f64>().map_err(|_| PhyloError::TreeParseError(format!("invalid {} '{}'", what, s)))
+}
+
+
+/// Create a genome entry for a name read from a tree file
+fn genome_from_name(name: &str) -> Genome {
+    let folder = format!("genomes/{}", name);
+
+    // point at the genome file if it's still around, otherwise at its folder
+    let dir = fs::read_dir(&folder).ok()
+        .and_then(|mut files| files.find_map(|f| {
+            let file_path = f.ok()?.path();
+            file_path.to_str().filter(|p| p.ends_with(".fna")).map(String::from)
+        }))
+        .unwrap_or(folder + "/");
+
+    Genome {
+        path: Vec::new(),
+        dir,
+        kmers: Vec::new(),
+        sketch: Sketch::default(),
+        spectrum: KmerSpectrum::default(),
+        closest_distance: f64::INFINITY,
+        length: None,
+    }
+}
+
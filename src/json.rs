@@ -0,0 +1,299 @@
+use std::fmt::{self, Display};
+
+use crate::errors::PhyloError;
+
+
+/// Minimal JSON value used for event logs and reports
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),   // keeps insertion order so output stays readable
+}
+impl JsonValue {
+
+    /// Start building an object from a list of key/value pairs
+    pub fn object(fields: Vec<(&str, JsonValue)>) -> Self {
+        JsonValue::Object(fields.into_iter().map(|(k, v)| (String::from(k), v)).collect())
+    }
+
+    /// Parse a JSON document, such as a report written by this program
+    pub fn parse(text: &str) -> Result<Self, PhyloError> {
+        let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+        let ret = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters after the document"));
+        }
+        Ok(ret)
+    }
+
+    /// The value stored under a key, if this is an object holding it
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            Self::Object(v) => v.iter().find(|(k, _)| k == key).map(|(_, elem)| elem),
+            _ => None,
+        }
+    }
+
+    /// The string, if this is one
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The number, if this is one, integers included
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(i) => Some(*i as f64),
+            Self::Float(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// The elements, if this is an array
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            Self::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => {
+                if x.is_finite() {
+                    write!(f, "{}", x)
+                } else { //JSON has no representation for infinities or NaN
+                    write!(f, "null")
+                }
+            },
+            Self::Str(s) => write_escaped(f, s),
+            Self::Array(v) => {
+                write!(f, "[")?;
+                for (i, elem) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, "]")
+            },
+            Self::Object(v) => {
+                write!(f, "{{")?;
+                for (i, (key, elem)) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_escaped(f, key)?;
+                    write!(f, ":{}", elem)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+impl From<bool> for JsonValue {
+    fn from(b: bool) -> Self {
+        JsonValue::Bool(b)
+    }
+}
+impl From<u8> for JsonValue {
+    fn from(i: u8) -> Self {
+        JsonValue::Int(i as i64)
+    }
+}
+impl From<u32> for JsonValue {
+    fn from(i: u32) -> Self {
+        JsonValue::Int(i as i64)
+    }
+}
+impl From<usize> for JsonValue {
+    fn from(i: usize) -> Self {
+        JsonValue::Int(i as i64)
+    }
+}
+impl From<f64> for JsonValue {
+    fn from(x: f64) -> Self {
+        JsonValue::Float(x)
+    }
+}
+impl From<&str> for JsonValue {
+    fn from(s: &str) -> Self {
+        JsonValue::Str(String::from(s))
+    }
+}
+impl From<String> for JsonValue {
+    fn from(s: String) -> Self {
+        JsonValue::Str(s)
+    }
+}
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(v: Vec<T>) -> Self {
+        JsonValue::Array(v.into_iter().map(|e| e.into()).collect())
+    }
+}
+
+
+/// Write a string with JSON escaping applied
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+
+/// Recursive descent parser over the bytes of a JSON document
+struct Parser<'a> {
+    bytes: &'a [u8],    // the whole document
+    pos: usize,         // the next byte to read
+}
+impl Parser<'_> {
+
+    /// Build an error pointing at the current position
+    fn error(&self, message: &str) -> PhyloError {
+        PhyloError::JsonParseError(format!("{} at byte {}", message, self.pos))
+    }
+
+    /// Move past any whitespace
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    /// Move past the expected literal, or fail
+    fn expect(&mut self, literal: &str) -> Result<(), PhyloError> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", literal)))
+        }
+    }
+
+    /// Read the value starting at the current position
+    fn value(&mut self) -> Result<JsonValue, PhyloError> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'n') => self.expect("null").map(|_| JsonValue::Null),
+            Some(b't') => self.expect("true").map(|_| JsonValue::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| JsonValue::Bool(false)),
+            Some(b'"') => self.string().map(JsonValue::Str),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut ret = Vec::new();
+                while !self.end_of_list(b']', ret.is_empty())? {
+                    ret.push(self.value()?);
+                }
+                Ok(JsonValue::Array(ret))
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut ret = Vec::new();
+                while !self.end_of_list(b'}', ret.is_empty())? {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    ret.push((key, self.value()?));
+                }
+                Ok(JsonValue::Object(ret))
+            },
+            Some(_) => self.number(),
+            None => Err(self.error("unexpected end of document")),
+        }
+    }
+
+    /// Check whether an array or object ends here, moving past the comma before any element but the first
+    fn end_of_list(&mut self, close: u8, first: bool) -> Result<bool, PhyloError> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b) if *b == close => {
+                self.pos += 1;
+                Ok(true)
+            },
+            Some(_) if first => Ok(false),
+            Some(b',') => {
+                self.pos += 1;
+                Ok(false)
+            },
+            _ => Err(self.error(&format!("expected ',' or '{}'", close as char))),
+        }
+    }
+
+    /// Read a string, undoing its escapes
+    fn string(&mut self) -> Result<String, PhyloError> {
+        self.expect("\"")?;
+        let mut ret = String::new();
+        loop {
+            let start = self.pos;
+            while self.pos < self.bytes.len() && !matches!(self.bytes[self.pos], b'"' | b'\\') {
+                self.pos += 1;
+            }
+            ret.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| self.error("invalid UTF-8 in string"))?);
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(ret);
+                },
+                Some(_) => { //an escape
+                    let escaped = *self.bytes.get(self.pos + 1).ok_or(self.error("unterminated escape"))?;
+                    self.pos += 2;
+                    match escaped {
+                        b'n' => ret.push('\n'),
+                        b'r' => ret.push('\r'),
+                        b't' => ret.push('\t'),
+                        b'b' => ret.push('\u{8}'),
+                        b'f' => ret.push('\u{c}'),
+                        b'u' => {
+                            let code = self.bytes.get(self.pos..self.pos+4)
+                                .and_then(|hex| u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                                .ok_or(self.error("invalid unicode escape"))?;
+                            ret.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)); //surrogate pairs are never written by this program
+                            self.pos += 4;
+                        },
+                        other => ret.push(other as char), //quotes, backslashes and slashes stand for themselves
+                    }
+                },
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Read a number, as an integer if it has no fraction or exponent
+    fn number(&mut self) -> Result<JsonValue, PhyloError> {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or_default();
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok(JsonValue::Int(i));
+        }
+        text.parse::<f64>().map(JsonValue::Float).map_err(|_| {
+            self.pos = start;
+            self.error("expected a value")
+        })
+    }
+}
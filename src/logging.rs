@@ -0,0 +1,190 @@
+use std::{env, fmt, fs::File, io::Write, sync::{Mutex, OnceLock}};
+
+use crate::{errors::PhyloError, json::JsonValue};
+
+
+/// Environment variable holding the log filter, e.g. "info,structs=debug,algorithms=trace"
+pub const LOG_ENV: &str = "GENOME_TREE_LOG";
+
+/// Environment variable holding the path of the JSON-lines event log, events are disabled when unset
+pub const EVENTS_ENV: &str = "GENOME_TREE_EVENTS";
+
+
+/// Severity of a log message, ordered from least to most verbose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Level {
+
+    /// Parse a level from its lowercase name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// The label printed in front of each message
+    fn label(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+
+/// Global logger state, configured once at startup
+#[derive(Debug)]
+struct Logger {
+    default: Level,                 // level used when no target override matches
+    targets: Vec<(String, Level)>,  // per-module overrides, keyed by module name without the crate prefix
+    events: Option<Mutex<File>>,    // JSON-lines event sink
+}
+impl Logger {
+
+    /// Decide the active level for the given module path
+    fn level_for(&self, module: &str) -> Level {
+        let target = strip_crate(module);
+        self.targets.iter()
+            .filter(|(t, _)| target == t || target.starts_with(&format!("{}::", t)))
+            .max_by_key(|(t, _)| t.len()) //the most specific target wins
+            .map(|(_, l)| *l)
+            .unwrap_or(self.default)
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+
+/// Configure logging from the environment, should be called once before any messages are logged
+pub fn init() -> Result<(), PhyloError> {
+    let spec = env::var(LOG_ENV).unwrap_or_default();
+    let events = env::var(EVENTS_ENV).ok();
+    init_with(&spec, events.as_deref())
+}
+
+
+/// Configure logging from a filter spec and an optional event log path
+pub fn init_with(spec: &str, events: Option<&str>) -> Result<(), PhyloError> {
+    let mut default = Level::Info;
+    let mut targets = Vec::new();
+
+    // parse each comma separated directive, either "level" or "target=level"
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                let level = Level::parse(level).ok_or(PhyloError::LogConfigError(String::from(directive)))?;
+                targets.push((String::from(target.trim()), level));
+            },
+            None => {
+                default = Level::parse(directive).ok_or(PhyloError::LogConfigError(String::from(directive)))?;
+            }
+        }
+    }
+
+    // open the event log if requested
+    let events = match events {
+        Some(path) => Some(Mutex::new(File::create(path).map_err(|_| PhyloError::FileOpenError(String::from(path)))?)),
+        None => None,
+    };
+
+    LOGGER.set(Logger { default, targets, events }).map_err(|_| PhyloError::LogConfigError(String::from("logging was already initialized")))
+}
+
+
+/// Check whether a message at the given level would be printed for the given module
+pub fn enabled(level: Level, module: &str) -> bool {
+    match LOGGER.get() {
+        Some(logger) => level <= logger.level_for(module),
+        None => level <= Level::Info,
+    }
+}
+
+
+/// Print a message to stderr, callers should go through the logging macros instead
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    eprintln!("[{} {}] {}", level.label(), strip_crate(module), args);
+}
+
+
+/// Check whether the event log is active, so callers can skip building events nobody will read
+pub fn events_enabled() -> bool {
+    matches!(LOGGER.get(), Some(Logger { events: Some(_), .. }))
+}
+
+
+/// Append an event to the event log as a single JSON line
+pub fn event(kind: &str, mut fields: JsonValue) {
+    if let Some(Logger { events: Some(file), .. }) = LOGGER.get() {
+        if let JsonValue::Object(ref mut v) = fields {
+            v.insert(0, (String::from("event"), JsonValue::from(kind)));
+        }
+        let mut file = file.lock().unwrap();
+        if writeln!(file, "{}", fields).is_err() {
+            eprintln!("[{} logging] failed to write to the event log", Level::Error.label());
+        }
+    }
+}
+
+
+/// Remove the crate name from a module path so targets read like the file names
+fn strip_crate(module: &str) -> &str {
+    match module.split_once("::") {
+        Some((_, rest)) => rest,
+        None => "main", //the crate root is main.rs
+    }
+}
+
+
+/// Log a message at the given level, the arguments are only formatted if the level is enabled
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::log($level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+/// Log a message at the error level
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Error, $($arg)+) };
+}
+
+/// Log a message at the warn level
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+/// Log a message at the info level
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Info, $($arg)+) };
+}
+
+/// Log a message at the debug level
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+/// Log a message at the trace level
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Trace, $($arg)+) };
+}
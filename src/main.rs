@@ -1,10 +1,32 @@
-use crate::structs::{PhyloTree, Genome};
-use std::{path::Path, env, fs::{self, File}, os::unix::prelude::FileExt, io::Write};
+use crate::{errors::PhyloError, structs::{Genome, PhyloTree}};
+use std::{env, fs::{self, File}, io::Write, path::Path};
 
 mod algorithms;
+mod alignment;
+mod ani;
+mod bases;
+mod bootstrap;
+mod compare;
+mod compression;
+mod consensus;
+mod distance;
+mod dust;
 mod errors;
+mod input;
+mod json;
+mod logging;
+mod metadata;
 mod structs;
+mod ordering;
 mod output;
+mod placement;
+mod rebalance;
+mod refine;
+mod rooting;
+mod sampling;
+mod segments;
+mod sketch;
+mod spectrum;
 
 
 /// A function dedicated to testing functionality
@@ -18,7 +40,7 @@ fn testing() {
 
     //let items = vec![1,  2, 3,  4,   5,   6, 7,  8];
     //let probs = vec![40, 3, 18, 100, 200, 1, 35, 16];
-    //dbg!(algorithms::random_weighted(items, probs, 10, true));
+    //dbg!(algorithms::random_weighted(items, probs, 10, true).unwrap());
 
     //let mut x = Box::new(3);
     //let y = &mut x;
@@ -73,53 +95,435 @@ fn testing() {
 }
 
 
-/// Handle all the tree generation
-fn tree_generation() {
-    let mut tree = structs::PhyloTree::new();
-    
+/// List every genome file in the genomes directory
+fn genome_files() -> Vec<String> {
+    let mut ret = Vec::new();
     let dir = env::current_dir().unwrap().to_str().unwrap().to_owned(); //get the current working directory
     let paths = fs::read_dir(dir + "/genomes").unwrap(); //get all paths in the genomes directory
 
     // iterate through all genome folders
     for path in paths {
-        //println!("Name: {}", path.unwrap().path().display());
         // iterate through all files in the genome folder
         for file in fs::read_dir(path.unwrap().path().to_str().unwrap()).unwrap() {
             let file_path = String::from(file.unwrap().path().to_str().unwrap());
             if !&file_path.ends_with(".fna") {
                 continue;
             }
-            //println!("{}", file_path);
-            let kmers = algorithms::generate_kmers(&file_path, 12, 64).unwrap();
-            let genome = Genome {
-                path: Vec::new(),
-                dir: file_path,
-                kmers: kmers,
-                closest_distance: 0,
-            };
+            ret.push(file_path);
+        }
+    }
+    ret
+}
 
-            println!("TOTAL GENOMES BEFORE PUSHING: {}", tree.root.count);
-            let res = tree.push(genome);
-            match res {
-                Ok(_) => {
-                    println!("SUCCESS: Pushed genome to tree without any problems");
-                    dbg!(&tree);
-                },
-                Err(e) => {
-                    println!("ERROR WHEN ATTEMPTING TO PUSH: {}", e);
-                    return;
-                }
-            }
 
+/// Handle all the tree generation
+fn tree_generation(options: &ordering::OrderOptions, model: distance::DistanceModel, kmers: &algorithms::KmerOptions, metadata: &metadata::Metadata, rebalance_options: Option<&rebalance::RebalanceOptions>, refine_options: Option<&refine::RefineOptions>, lengths: bool) {
+    let (mut tree, records) = match ordering::build_best(&genome_files(), options, model, kmers, metadata) {
+        Ok(built) => built,
+        Err(e) => {
+            log_error!("failed to push genome: {}", e);
+            return;
+        }
+    };
+    if let Some(rebalance_options) = rebalance_options {
+        if let Err(e) = rebalance::rebalance(&mut tree, rebalance_options) {
+            log_error!("failed to rebalance the tree: {}", e);
+            return;
+        }
+    }
+    if let Some(refine_options) = refine_options {
+        if let Err(e) = refine::refine(&mut tree, refine_options) { //refining fits the branch lengths as it goes
+            log_error!("failed to refine the tree: {}", e);
+            return;
+        }
+    } else if lengths {
+        if let Err(e) = tree.estimate_lengths() {
+            log_error!("failed to estimate branch lengths: {}", e);
+            return;
         }
     }
     output::output_tree(&tree.root).unwrap();
+    output::output_newick(&tree.root, "phylo_tree.nwk").unwrap();
+    output::output_report(&records).unwrap();
+}
+
+
+/// Load the tree and placement report written by the last build, leaving the query genome out of it
+fn saved_tree(query_path: &str, model: distance::DistanceModel, kmers: &algorithms::KmerOptions, metadata: &metadata::Metadata) -> Result<PhyloTree, PhyloError> {
+    fs::canonicalize(query_path).map_err(|_| PhyloError::FileOpenError(String::from(query_path)))?;
+    PhyloTree::load("phylo_tree.txt", "phylo_placements.json", model, kmers, metadata, Some(query_path)) //don't compare the query against itself
+}
+
+
+/// Load the reference tree and report where the given genome would land, without inserting it
+fn query_genome(query_path: &str, model: distance::DistanceModel, kmers: &algorithms::KmerOptions, metadata: &metadata::Metadata) -> Result<(), PhyloError> {
+    let tree = saved_tree(query_path, model, kmers, metadata)?;
+    let genome = Genome::load(query_path, kmers)?;
+    let record = tree.query(&genome)?;
+
+    println!("query:    {}", record.genome);
+    println!("case:     {}", record.case.label());
+    if let Some(ratio) = record.ratio {
+        println!("ratio:    {:.3}", ratio);
+    }
+    println!("descent:  {:?}", record.descent_path());
+    println!("path:     {:?}", record.path);
+    println!("relatives:");
+    for (name, distance) in &record.distances {
+        println!("    {}\t{}", distance, name);
+    }
+    if record.segments.len() > 1 {
+        println!("segments:");
+        for segment in &record.segments {
+            println!("    {}\t{}\t{} ({})\t{}", segment.segment, segment.distance, segment.relative, segment.matched, segment.closest_distance);
+        }
+        if let Some(driving) = &record.driving_segment {
+            println!("driving:  {}", driving);
+        }
+        let reassorted: Vec<&str> = record.reassorted().iter().map(|s| s.segment.as_str()).collect();
+        if !reassorted.is_empty() {
+            println!("reassorted: {}", reassorted.join(", "));
+        }
+    }
+    Ok(())
+}
+
+
+/// Load the reference tree and list the genomes most similar to the given genome
+fn nearest_genomes(query_path: &str, k: usize, refine: bool, model: distance::DistanceModel, kmers: &algorithms::KmerOptions, metadata: &metadata::Metadata) -> Result<(), PhyloError> {
+    let tree = saved_tree(query_path, model, kmers, metadata)?;
+    let genome = Genome::load(query_path, kmers)?;
+
+    for (rank, neighbour) in tree.nearest(&genome, k, refine)?.iter().enumerate() {
+        let distance = neighbour.distance.map(|d| d.to_string()).unwrap_or(String::from("-"));
+        println!("{}\t{}\t{}\t{}", rank + 1, neighbour.similarity, distance, neighbour.genome);
+    }
+    Ok(())
+}
+
+
+/// List the genome files whose genomes the sample contains, judged by their sketches
+fn contained_genomes(sample_path: &str, min_containment: f64, kmers: &algorithms::KmerOptions) -> Result<(), PhyloError> {
+    let sample_full = fs::canonicalize(sample_path).map_err(|_| PhyloError::FileOpenError(String::from(sample_path)))?;
+    let references = genome_files().into_iter()
+        .filter(|f| fs::canonicalize(f).map(|p| p != sample_full).unwrap_or(true)) //don't report the sample as containing itself
+        .map(|f| Ok((String::from(structs::genome_name(&f)), sketch::Sketch::read(&f, kmers)?)))
+        .collect::<Result<Vec<(String, sketch::Sketch)>, PhyloError>>()?;
+    let sample = sketch::Sketch::read(sample_path, kmers)?;
+
+    let sketches: Vec<(String, &sketch::Sketch)> = references.iter().map(|(name, s)| (name.clone(), s)).collect();
+    for found in sketch::contained_references(&sample, &sketches, min_containment) {
+        println!("{:.4}\t{:.4}\t{}/{}\t{}", found.containment, found.ani, found.shared, found.hashes, found.genome);
+    }
+    Ok(())
+}
+
+
+/// Estimate the average nucleotide identity of a genome to another one
+fn ani_genomes(query_path: &str, reference_path: &str) -> Result<(), PhyloError> {
+    let (query, reference) = (segments::read_sequence(query_path)?, segments::read_sequence(reference_path)?); //segments are laid end to end
+    let estimate = ani::ani(&query, &reference);
+    println!("query:     {}", query_path);
+    println!("reference: {}", reference_path);
+    println!("ani:       {:.4}", estimate.ani);
+    println!("aligned:   {:.4} ({} of {} fragments mapped)", estimate.aligned_fraction, estimate.mapped, estimate.fragments);
+    Ok(())
+}
+
+
+/// Align a genome against another one, or against its closest relative in the saved tree, and print the differences
+///
+/// A local alignment only covers the best matching region, which suits genomes that are partly assembled.
+fn align_genomes(query_path: &str, reference_path: Option<&String>, scoring: &alignment::Scoring, comparison: distance::Comparison, model: distance::DistanceModel, kmers: &algorithms::KmerOptions, metadata: &metadata::Metadata) -> Result<(), PhyloError> {
+    let reference_path = match reference_path {
+        Some(path) => path.clone(),
+        None => {
+            let mut tree = saved_tree(query_path, model, kmers, metadata)?;
+            let closest = tree.nearest(&Genome::load(query_path, kmers)?, 1, true)?.into_iter().next()
+                .ok_or(PhyloError::SearchGenomeError(String::from("no genomes to align against")))?;
+            algorithms::retrieve_genome(&mut tree.root, &closest.path)?.dir.clone()
+        }
+    };
+    let (reference, mut query) = (segments::read_sequence(&reference_path)?, segments::read_sequence(query_path)?); //segments are aligned end to end
+    let flags = [reference_path.as_str(), query_path].map(|p| metadata.flags(Path::new(p).parent().and_then(|d| d.file_name()).and_then(|n| n.to_str()).unwrap_or(p))); //genomes are named after their folder
+    let comparison = distance::Comparison { circular: comparison.circular || flags.iter().any(|f| f.circular), ..comparison };
+    println!("reference: {}", reference_path);
+    println!("query:     {}", query_path);
+    if comparison.circular {
+        let offset = distance::best_rotation(&reference, &query);
+        query = distance::rotate(&query, offset);
+        println!("rotation:  query starts at {}", offset + 1);
+    }
+    let (aligned, variants) = if comparison.local {
+        let aligned = alignment::local_align(&reference, &query, scoring, true);
+        println!("region:    reference {}-{}, query {}-{}", aligned.reference_start + 1, aligned.reference_end(), aligned.query_start + 1, aligned.query_end());
+        let variants = aligned.variants(&reference, &query);
+        (aligned.alignment, variants)
+    } else {
+        let aligned = alignment::align(&reference, &query, scoring);
+        let variants = aligned.variants(&reference, &query);
+        (aligned, variants)
+    };
+    println!("score:     {}", aligned.score);
+    println!("identity:  {:.4}", aligned.identity());
+    println!("cigar:     {}", aligned.cigar());
+    println!("variants:");
+    for variant in variants {
+        println!("    {}", variant);
+    }
+    Ok(())
+}
+
+
+/// Read two tree files and print how far apart they are, sampling the given number of quartets if any
+fn compare_tree_files(first: &str, second: &str, quartet_samples: Option<(u64, u64)>) -> Result<(), PhyloError> {
+    let first_tree = input::read_tree(first)?;
+    let second_tree = input::read_tree(second)?;
+    let comparison = compare::compare_trees(&first_tree, &second_tree, quartet_samples)?;
+
+    println!("shared genomes:           {}", comparison.shared);
+    println!("splits:                   {} / {}", comparison.splits_first, comparison.splits_second);
+    println!("Robinson-Foulds:          {}", comparison.robinson_foulds);
+    println!("normalized RF:            {:.4}", comparison.normalized_rf);
+    println!("weighted RF:              {:.4}", comparison.weighted_rf);
+    println!("matching split distance:  {}", comparison.matching_split);
+    match comparison.quartets {
+        Some(quartets) if quartets.exact => println!("quartet distance:         {} / {}", quartets.differing, quartets.total),
+        Some(quartets) => println!("quartet distance:         {:.4} of {} (estimated from {} sampled quartets, {} differ)", quartets.fraction(), quartets.total, quartets.checked, quartets.differing),
+        None => {}
+    }
+    Ok(())
+}
+
+
+/// Read a set of tree files and write their consensus next to them
+fn consensus_tree_files(rule: consensus::ConsensusRule, files: &[String]) -> Result<(), PhyloError> {
+    let trees = files.iter().map(|f| input::read_tree(f)).collect::<Result<Vec<_>, _>>()?;
+    let tree = consensus::consensus(&trees, rule)?;
+    output::output_tree_to(&tree, "phylo_consensus.txt")
+}
+
+
+/// Read a tree file, root it with the given method and write it next to it
+fn root_tree_file(file: &str, method: &str, outgroup: &[String], model: distance::DistanceModel, metadata: &metadata::Metadata) -> Result<(), PhyloError> {
+    let root = input::read_tree(file)?;
+    let mut tree = PhyloTree { root, next_index: 0, model, metadata: metadata.clone(), kmers: algorithms::KmerOptions::default() }; //the model and metadata are only used if branch lengths need estimating
+    match method {
+        "midpoint" => tree.root_midpoint()?,
+        "min-variance" => tree.root_min_variance()?,
+        "outgroup" => tree.root_outgroup(&outgroup.iter().map(String::as_str).collect::<Vec<_>>())?,
+        other => return Err(PhyloError::RootError(format!("unknown rooting method '{}'", other))),
+    }
+    output::output_tree_to(&tree.root, "phylo_rooted.txt")
+}
+
+
+/// Build the tree, rebuild it from resampled inputs and write it out with support values on every split
+fn bootstrap_tree(options: &bootstrap::BootstrapOptions) -> Result<(), PhyloError> {
+    let files = genome_files();
+    let (mut tree, records) = PhyloTree::from_files(&files, options.model, &options.kmers, &options.metadata)?;
+    bootstrap::bootstrap(&mut tree.root, &files, options)?;
+    output::output_tree(&tree.root)?;
+    output::output_report(&records)
+}
+
+
+/// Find the value following a flag such as --seed in the argument list
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1))
+}
+
+
+/// Read the --model flag, defaulting to raw Levenshtein distances
+fn model_flag(args: &[String]) -> Result<distance::DistanceModel, ()> {
+    flag_value(args, "--model").map(|a| distance::DistanceModel::parse(a).ok_or(())).unwrap_or(Ok(distance::DistanceModel::Raw))
+}
+
+
+/// Read the flags that change how kmers are sampled
+fn kmer_flags(args: &[String]) -> Result<algorithms::KmerOptions, ()> {
+    let defaults = algorithms::KmerOptions::default();
+    let number = |flag: &str, default: u32| flag_value(args, flag).map(|a| a.parse().map_err(|_| ())).unwrap_or(Ok(default));
+    Ok(algorithms::KmerOptions {
+        selection: flag_value(args, "--sampling").map(|a| algorithms::KmerSelection::parse(a).ok_or(())).unwrap_or(Ok(defaults.selection))?,
+        k: number("--kmer-size", defaults.k)?,
+        window: number("--window", defaults.window)?,
+        count: defaults.count,
+        skip_n: args.iter().any(|a| a == "--skip-n"),
+        mask: !args.iter().any(|a| a == "--no-mask"),
+        similarity: flag_value(args, "--similarity").map(|a| spectrum::KmerSimilarity::parse(a).ok_or(())).unwrap_or(Ok(defaults.similarity))?,
+        sketch_k: defaults.sketch_k,
+        scaled: number("--scaled", defaults.scaled)?,
+        seed: flag_value(args, "--seed").map(|a| a.parse().map_err(|_| ())).unwrap_or(Ok(defaults.seed))?,
+    })
 }
 
 
 /// Entry point
 fn main() {
-    testing();
-    //output::output_tree();
-    tree_generation();
+    if let Err(e) = logging::init() {
+        eprintln!("Failed to configure logging: {}", e);
+        return;
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let metadata = match flag_value(&args, "--metadata").map(|f| metadata::Metadata::read(f)).unwrap_or(Ok(metadata::Metadata::default())) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            log_error!("failed to read the metadata file: {}", e);
+            return;
+        }
+    };
+    let Ok(kmers) = kmer_flags(&args) else {
+        eprintln!("Kmer flags: [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N]");
+        return;
+    };
+    match args.get(1).map(String::as_str) {
+        None | Some("build") => {
+            let order = flag_value(&args, "--order").map(|a| ordering::InsertionOrder::parse(a).ok_or(())).unwrap_or(Ok(ordering::InsertionOrder::Discovery));
+            let seed = flag_value(&args, "--seed").map(|a| a.parse()).unwrap_or(Ok(0));
+            let attempts = flag_value(&args, "--attempts").map(|a| a.parse()).unwrap_or(Ok(1));
+            let objective = flag_value(&args, "--objective").map(|a| refine::Objective::parse(a).ok_or(())).unwrap_or(Ok(refine::Objective::LeastSquares));
+            let radius = flag_value(&args, "--spr-radius").map(|a| a.parse()).unwrap_or(Ok(4));
+            let (Ok(order), Ok(seed), Ok(attempts), Ok(objective), Ok(radius), Ok(model)) = (order, seed, attempts, objective, radius, model_flag(&args)) else {
+                eprintln!("Usage: genome-tree build [--order discovery|random|size|diversity|taxonomy] [--seed N] [--attempts N] [--taxonomy file.tsv] [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv] [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N] [--rebalance] [--refine] [--lengths] [--objective ls|me] [--spr-radius N]");
+                return;
+            };
+            let options = ordering::OrderOptions {
+                order,
+                seed,
+                attempts,
+                taxonomy: flag_value(&args, "--taxonomy").cloned(),
+            };
+            let refine_options = refine::RefineOptions {
+                objective,
+                nni: true,
+                spr: true,
+                spr_radius: radius,
+                max_rounds: 1000,
+                model,
+            };
+            let refine_options = args.iter().any(|a| a == "--refine").then_some(&refine_options);
+            let rebalance_options = rebalance::RebalanceOptions { min_chain: 3, tolerance: 1.17 };
+            let rebalance_options = args.iter().any(|a| a == "--rebalance").then_some(&rebalance_options);
+            testing();
+            //output::output_tree();
+            tree_generation(&options, model, &kmers, &metadata, rebalance_options, refine_options, args.iter().any(|a| a == "--lengths"));
+        },
+        Some("query") => {
+            let (Some(query_path), Ok(model)) = (args.get(2), model_flag(&args)) else {
+                eprintln!("Usage: genome-tree query <genome.fna> [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv] [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N]");
+                return;
+            };
+            if let Err(e) = query_genome(query_path, model, &kmers, &metadata) {
+                log_error!("query failed: {}", e);
+            }
+        },
+        Some("nearest") => {
+            let (Some(query_path), Ok(model)) = (args.get(2), model_flag(&args)) else {
+                eprintln!("Usage: genome-tree nearest <genome.fna> [k] [--refine] [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv] [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N]");
+                return;
+            };
+            let k = match args.get(3).filter(|a| !a.starts_with("--")) {
+                Some(k) => match k.parse() {
+                    Ok(k) => k,
+                    Err(_) => {
+                        eprintln!("Expected a number for k, found '{}'", k);
+                        return;
+                    }
+                },
+                None => 5,
+            };
+            let refine = args.iter().any(|a| a == "--refine");
+            if let Err(e) = nearest_genomes(query_path, k, refine, model, &kmers, &metadata) {
+                log_error!("nearest search failed: {}", e);
+            }
+        },
+        Some("align") => {
+            let defaults = alignment::Scoring::default();
+            let score = |flag: &str, default: i32| flag_value(&args, flag).map(|a| a.parse()).unwrap_or(Ok(default));
+            let scores = (score("--match", defaults.match_score), score("--mismatch", defaults.mismatch), score("--gap-open", defaults.gap_open), score("--gap-extend", defaults.gap_extend));
+            let (Some(query_path), (Ok(match_score), Ok(mismatch), Ok(gap_open), Ok(gap_extend)), Ok(model)) = (args.get(2), scores, model_flag(&args)) else {
+                eprintln!("Usage: genome-tree align <genome.fna> [reference.fna] [--match N] [--mismatch N] [--gap-open N] [--gap-extend N] [--local] [--circular] [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv] [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N]");
+                return;
+            };
+            let scoring = alignment::Scoring { match_score, mismatch, gap_open, gap_extend };
+            let reference_path = args.get(3).filter(|a| !a.starts_with("--"));
+            let comparison = distance::Comparison { local: args.iter().any(|a| a == "--local"), circular: args.iter().any(|a| a == "--circular") };
+            if let Err(e) = align_genomes(query_path, reference_path, &scoring, comparison, model, &kmers, &metadata) {
+                log_error!("alignment failed: {}", e);
+            }
+        },
+        Some("contains") => {
+            let min_containment = flag_value(&args, "--min-containment").map(|a| a.parse()).unwrap_or(Ok(0.1));
+            let (Some(sample_path), Ok(min_containment)) = (args.get(2), min_containment) else {
+                eprintln!("Usage: genome-tree contains <sample.fna> [--min-containment F] [--scaled N]");
+                return;
+            };
+            if let Err(e) = contained_genomes(sample_path, min_containment, &kmers) {
+                log_error!("containment search failed: {}", e);
+            }
+        },
+        Some("ani") => {
+            let (Some(query_path), Some(reference_path)) = (args.get(2), args.get(3)) else {
+                eprintln!("Usage: genome-tree ani <genome.fna> <reference.fna>");
+                return;
+            };
+            if let Err(e) = ani_genomes(query_path, reference_path) {
+                log_error!("ani estimation failed: {}", e);
+            }
+        },
+        Some("compare") => {
+            let samples = flag_value(&args, "--quartets").filter(|a| !a.starts_with("--")).map(|a| a.parse().map(Some)).unwrap_or(Ok(args.iter().any(|a| a == "--quartets").then_some(100000)));
+            let seed = flag_value(&args, "--seed").map(|a| a.parse()).unwrap_or(Ok(0));
+            let (Some(first), Some(second), Ok(samples), Ok(seed)) = (args.get(2), args.get(3), samples, seed) else {
+                eprintln!("Usage: genome-tree compare <tree_a.txt> <tree_b.txt> [--quartets [N]] [--seed N]");
+                return;
+            };
+            if let Err(e) = compare_tree_files(first, second, samples.map(|samples| (samples, seed))) {
+                log_error!("comparison failed: {}", e);
+            }
+        },
+        Some("bootstrap") => {
+            let replicates = args.get(2).filter(|a| !a.starts_with("--")).map(|a| a.parse()).unwrap_or(Ok(100));
+            let seed = flag_value(&args, "--seed").map(|a| a.parse()).unwrap_or(Ok(0));
+            let (Ok(replicates), Ok(seed), Ok(model)) = (replicates, seed, model_flag(&args)) else {
+                eprintln!("Usage: genome-tree bootstrap [replicates] [--seed N] [--keep-order] [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv] [--sampling minimizer|random] [--kmer-size N] [--window N] [--skip-n] [--no-mask] [--similarity sampled|jaccard|containment|cosine|sketch] [--scaled N]");
+                return;
+            };
+            let options = bootstrap::BootstrapOptions {
+                replicates,
+                seed,
+                shuffle: !args.iter().any(|a| a == "--keep-order"),
+                model,
+                kmers,
+                metadata,
+            };
+            if let Err(e) = bootstrap_tree(&options) {
+                log_error!("bootstrap failed: {}", e);
+            }
+        },
+        Some("consensus") => {
+            let Some(rule) = args.get(2).and_then(|a| consensus::ConsensusRule::parse(a)) else {
+                eprintln!("Usage: genome-tree consensus <majority|strict> <tree files...>");
+                return;
+            };
+            if let Err(e) = consensus_tree_files(rule, &args[3..]) {
+                log_error!("consensus failed: {}", e);
+            }
+        },
+        Some("root") => {
+            let (Some(file), Some(method), Ok(model)) = (args.get(2), args.get(3), model_flag(&args)) else {
+                eprintln!("Usage: genome-tree root <tree.txt> <midpoint|min-variance|outgroup> [genome names...] [--model raw|p|jc|k2p|tn93|ani|ncd] [--metadata file.tsv]");
+                return;
+            };
+            let names: Vec<String> = args[4..].iter().take_while(|a| !a.starts_with("--")).cloned().collect();
+            if let Err(e) = root_tree_file(file, method, &names, model, &metadata) {
+                log_error!("rooting failed: {}", e);
+            }
+        },
+        Some(other) => {
+            eprintln!("Unknown command '{}', expected one of: build, query, nearest, contains, ani, align, compare, bootstrap, consensus, root", other);
+        }
+    }
 }
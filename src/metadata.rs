@@ -0,0 +1,48 @@
+use std::{collections::HashMap, fs};
+
+use crate::errors::PhyloError;
+
+
+/// Facts about a genome that can't be read from its sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenomeFlags {
+    pub partial: bool,      // the assembly only covers part of the genome, such as a single segment or a contig
+    pub circular: bool,     // the genome is a circle, linearized at an arbitrary origin
+}
+
+
+/// Flags for every genome listed in a metadata file, genomes that aren't listed get the defaults
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    flags: HashMap<String, GenomeFlags>,
+}
+impl Metadata {
+
+    /// Read a metadata file, every line holds a genome name, a tab, then its flags separated by ','
+    ///
+    /// Lines starting with '#' are skipped, as are flags this version doesn't know about.
+    pub fn read(file_dir: &str) -> Result<Self, PhyloError> {
+        let contents = fs::read_to_string(file_dir).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?;
+        let mut flags = HashMap::new();
+        for line in contents.lines().filter(|l| !l.starts_with('#')) {
+            let Some((name, list)) = line.split_once('\t') else {
+                continue;
+            };
+            let mut genome = GenomeFlags::default();
+            for flag in list.split(',').map(str::trim) {
+                match flag {
+                    "partial" => genome.partial = true,
+                    "circular" => genome.circular = true,
+                    _ => {}
+                }
+            }
+            flags.insert(String::from(name.trim()), genome);
+        }
+        Ok(Metadata { flags })
+    }
+
+    /// The flags of the named genome
+    pub fn flags(&self, name: &str) -> GenomeFlags {
+        self.flags.get(name).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,242 @@
+use std::{collections::{BTreeMap, HashMap}, fs};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{algorithms::{self, KmerOptions}, distance::DistanceModel, errors::PhyloError, metadata::Metadata, placement::PlacementRecord, refine::{self, Arena}, segments, structs::{self, Genome, PhyloTree}, log_info};
+
+
+/// The order genome files are inserted into the tree in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertionOrder {
+    Discovery,      // whatever order the files were listed in
+    Random,         // shuffled with the seed
+    Size,           // largest genome first
+    Diversity,      // farthest-first, every genome is the one least like those already picked
+    Taxonomy,       // one genome per lineage at a time, so every lineage gets a branch early
+}
+impl InsertionOrder {
+
+    /// Parse an ordering from its name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "discovery" => Some(InsertionOrder::Discovery),
+            "random" => Some(InsertionOrder::Random),
+            "size" => Some(InsertionOrder::Size),
+            "diversity" => Some(InsertionOrder::Diversity),
+            "taxonomy" => Some(InsertionOrder::Taxonomy),
+            _ => None,
+        }
+    }
+}
+
+
+/// Settings for deciding the insertion order of a build
+#[derive(Debug, Clone)]
+pub struct OrderOptions {
+    pub order: InsertionOrder,      // how to order the files
+    pub seed: u64,                  // seed for the random parts of the ordering, attempt i uses seed + i
+    pub attempts: u32,              // number of trees to build, the one that fits the pairwise distances best is kept
+    pub taxonomy: Option<String>,   // tab separated file of genome names and ';' separated lineages
+}
+
+
+/// Build a tree once per attempt and keep the one that fits the pairwise distances best
+///
+/// The descent compares every genome against candidates drawn at random, and kmers may be sampled at
+/// random too, both with the kmer seed plus the attempt, so even orderings that don't depend on the seed
+/// can give a different tree on each attempt. With more than one attempt every pair of genomes is
+/// compared once, and each tree is scored by the squared error of its least squares branch lengths.
+pub fn build_best(files: &[String], options: &OrderOptions, model: DistanceModel, kmers: &KmerOptions, metadata: &Metadata) -> Result<(PhyloTree, Vec<PlacementRecord>), PhyloError> {
+    let attempts = options.attempts.max(1);
+    let mut best: Option<(f64, PhyloTree, Vec<PlacementRecord>)> = None;
+    let mut index: HashMap<String, usize> = HashMap::new(); //rows of the distances, measured on the first tree's genomes
+    let mut distances = Vec::new();
+
+    for attempt in 0..attempts {
+        let order = order_files(files, options, kmers, attempt)?;
+        let kmers = KmerOptions { seed: kmers.seed.wrapping_add(attempt as u64), ..*kmers };
+        let (tree, records) = PhyloTree::from_files(&order, model, &kmers, metadata)?;
+        if attempts == 1 {
+            return Ok((tree, records));
+        }
+
+        let mut genomes = Vec::new();
+        let arena = Arena::from_tree(&tree.root, &mut genomes);
+        if index.is_empty() {
+            index = genomes.iter().enumerate().map(|(i, g)| (g.dir.clone(), i)).collect();
+            distances = refine::pairwise_distances(&genomes, model, metadata)?;
+        }
+        let cost = tree_error(&arena, &genomes, &index, &distances)?;
+        log_info!("attempt {} of {} fits the distances with a squared error of {}", attempt + 1, attempts, cost);
+
+        if best.as_ref().map(|(c, _, _)| cost < *c).unwrap_or(true) {
+            best = Some((cost, tree, records));
+        }
+    }
+    let (_, tree, records) = best.unwrap();
+    Ok((tree, records))
+}
+
+
+/// Squared error of the least squares branch lengths of a flattened tree, lower is better
+///
+/// The distances are indexed by file, so trees that list their genomes in a different order share them.
+pub fn tree_error(arena: &Arena, genomes: &[Genome], index: &HashMap<String, usize>, distances: &[Vec<f64>]) -> Result<f64, PhyloError> {
+    let rows = genomes.iter()
+        .map(|g| index.get(&g.dir).copied().ok_or_else(|| PhyloError::RefineError(format!("no distances measured for {}", g.name()))))
+        .collect::<Result<Vec<_>, PhyloError>>()?;
+    let reordered: Vec<Vec<f64>> = rows.iter().map(|i| rows.iter().map(|j| distances[*i][*j]).collect()).collect();
+    Ok(arena.fit_lengths(&reordered).1)
+}
+
+
+/// Arrange the files in the order given by the options
+pub fn order_files(files: &[String], options: &OrderOptions, kmers: &KmerOptions, attempt: u32) -> Result<Vec<String>, PhyloError> {
+    let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(attempt as u64));
+    match options.order {
+        InsertionOrder::Discovery => Ok(files.to_vec()),
+        InsertionOrder::Random => {
+            let mut ret = files.to_vec();
+            ret.shuffle(&mut rng);
+            Ok(ret)
+        },
+        InsertionOrder::Size => by_size(files),
+        InsertionOrder::Diversity => {
+            // the first attempt starts from the largest genome, later ones from a random genome
+            let start = if attempt == 0 || files.is_empty() { None } else { Some(rng.gen_range(0..files.len())) };
+            farthest_first(files, start, kmers)
+        },
+        InsertionOrder::Taxonomy => {
+            let lineages = match &options.taxonomy {
+                Some(file) => read_taxonomy(file)?,
+                None => HashMap::new(),
+            };
+            Ok(by_taxonomy(files, &lineages))
+        }
+    }
+}
+
+
+/// Sort the files by size, largest first
+fn by_size(files: &[String]) -> Result<Vec<String>, PhyloError> {
+    let mut sized = files.iter()
+        .map(|f| Ok((algorithms::file_size(f)?, f.clone())))
+        .collect::<Result<Vec<_>, PhyloError>>()?;
+    sized.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    Ok(sized.into_iter().map(|(_, f)| f).collect())
+}
+
+
+/// Farthest-first traversal over kmer distances, starting from the given file or the largest one
+///
+/// Every file is read once and kept in memory, and every pair is compared through its kmers.
+fn farthest_first(files: &[String], start: Option<usize>, kmers: &KmerOptions) -> Result<Vec<String>, PhyloError> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // load every genome along with its full sequence
+    let mut genomes = Vec::with_capacity(files.len());
+    for file in files {
+        let genome = Genome::load(file, kmers)?;
+        let sequence = segments::sequence_text(file)?;
+        genomes.push((genome, sequence));
+    }
+
+    // distance is the number of kmers missing from the other genome, counted both ways
+    let distance = |a: usize, b: usize| -> u32 {
+        let (ga, sa) = &genomes[a];
+        let (gb, sb) = &genomes[b];
+        (ga.kmers.len() + gb.kmers.len()) as u32 - algorithms::kmer_hits(&ga.kmers, sb) - algorithms::kmer_hits(&gb.kmers, sa)
+    };
+
+    let first = match start {
+        Some(i) => i,
+        None => (0..genomes.len()).max_by_key(|i| (genomes[*i].1.len(), std::cmp::Reverse(*i))).unwrap(),
+    };
+    let mut order = vec![first];
+    let mut closest: Vec<u32> = (0..genomes.len()).map(|i| distance(first, i)).collect(); //distance to the nearest picked genome
+    let mut picked = vec![false; genomes.len()];
+    picked[first] = true;
+
+    // keep picking the genome furthest from everything picked so far
+    while order.len() < genomes.len() {
+        let next = (0..genomes.len())
+            .filter(|i| !picked[*i])
+            .max_by_key(|i| (closest[*i], std::cmp::Reverse(*i)))
+            .unwrap();
+        picked[next] = true;
+        order.push(next);
+        for i in 0..genomes.len() {
+            if !picked[i] {
+                closest[i] = closest[i].min(distance(next, i));
+            }
+        }
+    }
+    Ok(order.into_iter().map(|i| files[i].clone()).collect())
+}
+
+
+/// Read a taxonomy file, every line holds a genome name, a tab, then its lineage separated by ';'
+pub fn read_taxonomy(file_dir: &str) -> Result<HashMap<String, String>, PhyloError> {
+    let contents = fs::read_to_string(file_dir).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?;
+    Ok(contents.lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(name, lineage)| (String::from(name.trim()), String::from(lineage.trim())))
+        .collect())
+}
+
+
+/// Deal the files out one lineage at a time, so the first genomes inserted cover as many lineages as possible
+///
+/// Genomes missing from the taxonomy fall back to the first word of their name, which for most viruses
+/// is the host or genus.
+fn by_taxonomy(files: &[String], lineages: &HashMap<String, String>) -> Vec<String> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let name = structs::genome_name(file);
+        let lineage = lineages.get(name).cloned()
+            .unwrap_or_else(|| String::from(name.split('_').next().unwrap_or(name)));
+        groups.entry(lineage).or_default().push(file.clone());
+    }
+
+    // take one file from every lineage per round
+    let mut groups: Vec<std::vec::IntoIter<String>> = groups.into_values().map(|g| g.into_iter()).collect();
+    let mut ret = Vec::with_capacity(files.len());
+    while ret.len() < files.len() {
+        for group in groups.iter_mut() {
+            if let Some(file) = group.next() {
+                ret.push(file);
+            }
+        }
+    }
+    ret
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| format!("genomes/{}/genome.fna", n)).collect()
+    }
+
+    #[test]
+    fn orders_are_parsed_by_name() {
+        assert_eq!(InsertionOrder::parse("diversity"), Some(InsertionOrder::Diversity));
+        assert_eq!(InsertionOrder::parse("taxonomy"), Some(InsertionOrder::Taxonomy));
+        assert_eq!(InsertionOrder::parse("Random"), None);
+    }
+
+    #[test]
+    fn taxonomy_deals_one_genome_per_lineage() {
+        let lineages = HashMap::from([
+            (String::from("a1"), String::from("Viruses;Alpha")),
+            (String::from("a2"), String::from("Viruses;Alpha")),
+            (String::from("b1"), String::from("Viruses;Beta")),
+        ]);
+        // c_1 and c_2 are missing from the taxonomy and are grouped by the first word of their names
+        let order = by_taxonomy(&files(&["a1", "a2", "c_1", "b1", "c_2"]), &lineages);
+        assert_eq!(order, files(&["a1", "b1", "c_1", "a2", "c_2"]));
+    }
+}
@@ -1,6 +1,6 @@
 use std::{path::Path, fs::{self, File}, io::Write};
 
-use crate::{structs::{TreeNode, TreeVertex}, errors::PhyloError};
+use crate::{structs::{TreeNode, TreeVertex}, errors::PhyloError, placement::PlacementRecord};
 
 
 /// Produce an output file from a TreeNode
@@ -9,15 +9,33 @@ pub fn output_tree(root: &TreeNode) -> Result<(), PhyloError> {
     if path.exists() {
         fs::remove_file("phylo_tree.txt").map_err(|_| PhyloError::FileDeleteError)?; //if the output file exists already, override it
     }
-    let mut file = File::create(path).map_err(|_| PhyloError::FileOpenError(String::from("Error opening the output file")))?;
+    output_tree_to(root, "phylo_tree.txt")
+}
+
+/// Produce an output file from a TreeNode at the given location
+pub fn output_tree_to(root: &TreeNode, file_dir: &str) -> Result<(), PhyloError> {
+    let mut file = File::create(file_dir).map_err(|_| PhyloError::FileOpenError(String::from("Error opening the output file")))?;
     output_tree_recursive(root, &mut file, 0)
 }
 
+/// Produce the placement report, one JSON record per inserted genome, next to the tree file
+pub fn output_report(records: &[PlacementRecord]) -> Result<(), PhyloError> {
+    let mut file = File::create("phylo_placements.json").map_err(|_| PhyloError::FileOpenError(String::from("Error opening the report file")))?;
+
+    // write one record per line inside a JSON array, so the file stays both valid JSON and easy to grep
+    file.write_all(b"[\n").map_err(|_| PhyloError::FileWriteError)?;
+    for (i, record) in records.iter().enumerate() {
+        let separator = if i + 1 < records.len() { ",\n" } else { "\n" };
+        file.write_all((record.to_json().to_string() + separator).as_bytes()).map_err(|_| PhyloError::FileWriteError)?;
+    }
+    file.write_all(b"]\n").map_err(|_| PhyloError::FileWriteError)
+}
+
 /// Internal recursive function that handles tree construction without worrying about initial conditions
 fn output_tree_recursive(root: &TreeNode, file: &mut File, tabs: usize) -> Result<(), PhyloError> {
     match &root.vertex { //first find the type of node we're dealing with
         TreeVertex::Split(s) => {
-            file.write_all((vec![' '; tabs*4].iter().collect::<String>() + "split:\n").as_bytes()) //write the node type
+            file.write_all((vec![' '; tabs*4].iter().collect::<String>() + "split:" + &support_label(root) + &length_label(root.length) + "\n").as_bytes()) //write the node type
                 .map_err(|_| PhyloError::FileWriteError)?; //write the node type
 
             // for each node in this split, run the function again
@@ -26,7 +44,7 @@ fn output_tree_recursive(root: &TreeNode, file: &mut File, tabs: usize) -> Resul
             }
         },
         TreeVertex::Floor(f) => {
-            file.write_all((vec![' '; tabs*4].iter().collect::<String>() + "floor:\n").as_bytes()) //write the node type
+            file.write_all((vec![' '; tabs*4].iter().collect::<String>() + "floor:" + &support_label(root) + &length_label(root.length) + "\n").as_bytes()) //write the node type
                 .map_err(|_| PhyloError::FileWriteError)?; //write the node type
             
             // for each genome in this floor, print to file
@@ -37,9 +55,98 @@ fn output_tree_recursive(root: &TreeNode, file: &mut File, tabs: usize) -> Resul
                 dir = dir[slash_loc+1..].into();
 
                 // write to file
-                file.write_all((vec![' '; tabs*4+4].iter().collect::<String>() + &dir + "\n").as_bytes()).map_err(|_| PhyloError::FileWriteError)?;
+                file.write_all((vec![' '; tabs*4+4].iter().collect::<String>() + &dir + &length_label(genome.length) + "\n").as_bytes()).map_err(|_| PhyloError::FileWriteError)?;
             }
         }
     }
     Ok(())
 }
+
+
+/// Format the support value of a node for the tree file, empty if it was never measured
+fn support_label(node: &TreeNode) -> String {
+    match node.support {
+        Some(support) => format!(" {:.2}", support),
+        None => String::new(),
+    }
+}
+
+
+/// Format a branch length for the tree file, empty if it was never estimated
+fn length_label(length: Option<f64>) -> String {
+    match length {
+        Some(length) => format!(" length={:.6}", length),
+        None => String::new(),
+    }
+}
+
+
+/// Write the tree in Newick format, with support values as node labels and branch lengths where known
+///
+/// A floor holding a single genome is written as just that genome, with the floor's edge added to its own.
+pub fn output_newick(root: &TreeNode, file_dir: &str) -> Result<(), PhyloError> {
+    let mut file = File::create(file_dir).map_err(|_| PhyloError::FileOpenError(String::from("Error opening the Newick file")))?;
+    file.write_all((newick_recursive(root) + ";\n").as_bytes()).map_err(|_| PhyloError::FileWriteError)
+}
+
+
+/// Internal recursive function that formats a node and everything under it in Newick format
+fn newick_recursive(node: &TreeNode) -> String {
+    let support = node.support.map(|s| format!("{:.2}", s)).unwrap_or_default();
+    match &node.vertex {
+        TreeVertex::Split(s) => {
+            let children: Vec<String> = s.iter().map(newick_recursive).collect();
+            format!("({}){}{}", children.join(","), support, newick_length(node.length))
+        },
+        TreeVertex::Floor(f) if f.len() == 1 => {
+            let length = node.length.map(|l| l + f[0].length.unwrap_or(0.0));
+            format!("{}{}", newick_name(f[0].name()), newick_length(length))
+        },
+        TreeVertex::Floor(f) => {
+            let genomes: Vec<String> = f.iter().map(|g| format!("{}{}", newick_name(g.name()), newick_length(g.length))).collect();
+            format!("({}){}{}", genomes.join(","), support, newick_length(node.length))
+        }
+    }
+}
+
+
+/// Quote a genome name for Newick if it holds any characters with a meaning in the format
+fn newick_name(name: &str) -> String {
+    if name.chars().any(|c| "()[]':;, \t".contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        String::from(name)
+    }
+}
+
+
+/// Format a branch length for Newick, empty if it was never estimated
+fn newick_length(length: Option<f64>) -> String {
+    length.map(|l| format!(":{:.6}", l)).unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::parse_tree;
+
+    #[test]
+    fn newick_merges_single_genome_floors() {
+        let tree = parse_tree("split: 0.75 length=1
+    floor: length=2
+        A length=0.5
+    floor:
+        B
+        C length=1
+").unwrap();
+        assert_eq!(newick_recursive(&tree), "(A:2.500000,(B,C:1.000000))0.75:1.000000");
+    }
+
+    #[test]
+    fn newick_names_are_quoted() {
+        assert_eq!(newick_name("GCF_000820495.2"), "GCF_000820495.2");
+        assert_eq!(newick_name("a b"), "'a b'");
+        assert_eq!(newick_name("it's"), "'it''s'");
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{distance::SegmentMatch, json::JsonValue};
+
+
+/// Which branch of the insertion step placed a genome
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementCase {
+    First,          // case 0, the tree was empty
+    NewPair,        // case 1, new branch holding the genome and its closest relative
+    OnlyMember,     // case 1.1, case 1 where the closest relative was alone in its floor
+    NewBranch,      // case 2, new branch holding only the genome
+    SameBranch,     // case 3, genome joins the floor of its closest relative
+}
+impl PlacementCase {
+
+    /// The label used for this case in logs and reports
+    pub fn label(&self) -> &'static str {
+        match self {
+            PlacementCase::First => "0",
+            PlacementCase::NewPair => "1",
+            PlacementCase::OnlyMember => "1.1",
+            PlacementCase::NewBranch => "2",
+            PlacementCase::SameBranch => "3",
+        }
+    }
+}
+
+
+/// One round of the descent through the tree
+#[derive(Debug, Clone)]
+pub struct DescentStep {
+    pub node: u8,                       // the node whose genomes were sampled
+    pub count: u32,                     // the number of genomes under that node
+    pub candidates: Vec<(String, f64)>, // (genome name, k-mer similarity) for every sampled genome
+    pub best: String,                   // the most similar candidate, which decides where to descend next
+}
+impl DescentStep {
+
+    /// Convert the step into JSON
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("node", self.node.into()),
+            ("count", self.count.into()),
+            ("candidates", JsonValue::Array(self.candidates.iter().map(|(name, sim)| JsonValue::object(vec![
+                ("genome", name.as_str().into()),
+                ("similarity", (*sim).into()),
+            ])).collect())),
+            ("best", self.best.as_str().into()),
+        ])
+    }
+}
+
+
+/// A genome found by a nearest neighbour search
+#[derive(Debug, Clone)]
+pub struct Neighbour {
+    pub genome: String,             // name of the genome in the tree
+    pub path: Vec<u8>,              // path of the genome in the tree
+    pub similarity: u32,            // number of the genome's kmers found in the query
+    pub distance: Option<f64>,      // distance to the query, only set when the search was refined
+}
+
+
+/// How one segment of a placed genome matched the final candidates
+#[derive(Debug, Clone)]
+pub struct SegmentPlacement {
+    pub segment: String,            // name of the segment
+    pub relative: String,           // the candidate holding the segment's closest match
+    pub matched: String,            // name of the matching segment in that candidate
+    pub distance: f64,              // distance to that match
+    pub closest_distance: f64,      // distance to its best match in the chosen closest relative
+}
+impl SegmentPlacement {
+
+    /// Work out the segment report of a placement from the segment matches of every candidate
+    ///
+    /// Also returns the segment driving the placement: the one whose match in the closest relative beats
+    /// its best match in any other candidate by the widest margin. There's none with a single candidate.
+    pub fn from_candidates(candidates: &[(&str, &[SegmentMatch])], closest: &str) -> (Vec<Self>, Option<String>) {
+        let Some((_, closest_matches)) = candidates.iter().find(|(name, _)| *name == closest) else {
+            return (Vec::new(), None);
+        };
+
+        let mut ret = Vec::with_capacity(closest_matches.len());
+        let mut driving: Option<(f64, String)> = None;
+        for (i, own) in closest_matches.iter().enumerate() {
+            let mut best = (own.distance, closest, &own.matched);
+            let mut runner_up = f64::INFINITY;
+            for (name, matches) in candidates.iter().filter(|(name, _)| *name != closest) {
+                let Some(other) = matches.get(i) else {
+                    continue;
+                };
+                runner_up = runner_up.min(other.distance);
+                if other.distance < best.0 {
+                    best = (other.distance, name, &other.matched);
+                }
+            }
+            let margin = runner_up - own.distance;
+            if runner_up.is_finite() && driving.as_ref().map(|(m, _)| margin > *m).unwrap_or(true) {
+                driving = Some((margin, own.segment.clone()));
+            }
+            ret.push(SegmentPlacement {
+                segment: own.segment.clone(),
+                relative: String::from(best.1),
+                matched: best.2.clone(),
+                distance: best.0,
+                closest_distance: own.distance,
+            });
+        }
+        (ret, driving.map(|(_, segment)| segment))
+    }
+
+    /// Convert the segment into JSON
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("segment", self.segment.as_str().into()),
+            ("relative", self.relative.as_str().into()),
+            ("matched", self.matched.as_str().into()),
+            ("distance", self.distance.into()),
+            ("closest_distance", self.closest_distance.into()),
+        ])
+    }
+}
+
+
+/// Audit record describing how a single genome was placed by PhyloTree::push
+#[derive(Debug, Clone)]
+pub struct PlacementRecord {
+    pub genome: String,                     // name of the placed genome
+    pub descent: Vec<DescentStep>,          // every round of the descent, in order
+    pub final_node: u8,                     // the node the insertion step ran on
+    pub distances: Vec<(String, f64)>,      // (genome name, distance) for the final candidates
+    pub closest: Option<String>,            // the chosen closest relative, none for the first genome
+    pub ratio: Option<f64>,                 // new distance over the closest relative's previous distance
+    pub case: PlacementCase,                // which insertion case fired
+    pub path: Vec<u8>,                      // path of the genome right after insertion
+    pub segments: Vec<SegmentPlacement>,    // how every segment matched the candidates
+    pub driving_segment: Option<String>,    // the segment that most sets the closest relative apart
+}
+impl PlacementRecord {
+
+    /// Create the record for the first genome of a tree
+    pub fn first(genome: String, path: Vec<u8>) -> Self {
+        PlacementRecord {
+            genome,
+            descent: Vec::new(),
+            final_node: 0,
+            distances: Vec::new(),
+            closest: None,
+            ratio: None,
+            case: PlacementCase::First,
+            path,
+            segments: Vec::new(),
+            driving_segment: None,
+        }
+    }
+
+    /// The node ids visited during the descent, ending with the node the insertion ran on
+    pub fn descent_path(&self) -> Vec<u8> {
+        let mut ret: Vec<u8> = self.descent.iter().map(|s| s.node).collect();
+        if ret.last() != Some(&self.final_node) {
+            ret.push(self.final_node);
+        }
+        ret
+    }
+
+    /// Segments whose closest match lies in a genome other than the chosen closest relative
+    ///
+    /// For segmented viruses these hint at reassortment.
+    pub fn reassorted(&self) -> Vec<&SegmentPlacement> {
+        self.segments.iter().filter(|s| Some(&s.relative) != self.closest.as_ref()).collect()
+    }
+
+    /// Convert the record into JSON
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object(vec![
+            ("genome", self.genome.as_str().into()),
+            ("descent_path", self.descent_path().into()),
+            ("descent", JsonValue::Array(self.descent.iter().map(|s| s.to_json()).collect())),
+            ("distances", JsonValue::Array(self.distances.iter().map(|(name, d)| JsonValue::object(vec![
+                ("genome", name.as_str().into()),
+                ("distance", (*d).into()),
+            ])).collect())),
+            ("closest", self.closest.clone().map(JsonValue::from).unwrap_or(JsonValue::Null)),
+            ("ratio", self.ratio.map(JsonValue::from).unwrap_or(JsonValue::Null)),
+            ("case", self.case.label().into()),
+            ("path", self.path.clone().into()),
+            ("segments", JsonValue::Array(self.segments.iter().map(|s| s.to_json()).collect())),
+            ("driving_segment", self.driving_segment.clone().map(JsonValue::from).unwrap_or(JsonValue::Null)),
+            ("reassorted", JsonValue::Array(self.reassorted().iter().map(|s| s.segment.as_str().into()).collect())),
+        ])
+    }
+}
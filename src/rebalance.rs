@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+
+use crate::{distance::DistanceModel, errors::PhyloError, metadata::Metadata, refine, structs::{Genome, PhyloTree, TreeNode, TreeVertex}, log_debug, log_info};
+
+
+/// Settings for flattening caterpillar chains
+#[derive(Debug, Clone)]
+pub struct RebalanceOptions {
+    pub min_chain: usize,   // shortest chain of single-genome floors worth rebalancing
+    pub tolerance: f64,     // genomes within this ratio of the closest distance to the clade share a level with it
+}
+
+
+/// Rebalance every chain of single-genome floors in the tree, returns how many chains were rebalanced
+///
+/// Repeated case 2 insertions next to the same floor nest each new genome one split above the last, so
+/// the order of the chain is the insertion order. Here genomes of a chain that are closer to each other
+/// than to the clade at its bottom are first grouped into a floor of their own. The groups are then
+/// stacked back onto the clade one level at a time: every level takes the group with the smallest mean
+/// distance to the genomes already in the subtree, along with every group within the tolerance of that
+/// distance, since nothing tells their order apart. Every genome of a chain is compared against every
+/// genome in the subtree under it, with the distance model of the tree.
+pub fn rebalance(tree: &mut PhyloTree, options: &RebalanceOptions) -> Result<u32, PhyloError> {
+    let count = rebalance_node(&mut tree.root, tree.model, &tree.metadata, options)?;
+    if count > 0 {
+        tree.next_index = tree.root.renumber(0)?;
+    }
+    log_info!("rebalanced {} chains", count);
+    Ok(count)
+}
+
+
+/// Internal recursive function that rebalances the chain starting at a node, then everything below it
+fn rebalance_node(node: &mut TreeNode, model: DistanceModel, metadata: &Metadata, options: &RebalanceOptions) -> Result<u32, PhyloError> {
+    if chain_length(node) < options.min_chain.max(2) {
+        let mut count = 0;
+        if let TreeVertex::Split(s) = &mut node.vertex {
+            for child in s {
+                count += rebalance_node(child, model, metadata, options)?;
+            }
+        }
+        return Ok(count);
+    }
+
+    let head = std::mem::replace(node, TreeNode::new_with_floor(0, 0));
+    let (mut core, mut leaves) = unwind_chain(head);
+    log_debug!("rebalancing a chain of {} genomes", leaves.len());
+    let count = rebalance_node(&mut core, model, metadata, options)? + 1; //the genomes of the chain hang directly off it, so only the clade at the bottom can hold more chains
+    leaves.reverse(); //deepest genome first, so insertion order decides between equal distances
+
+    // measure every genome of the chain against the clade and the rest of the chain
+    let mut columns = Vec::new();
+    clade_genomes(&core, &mut columns);
+    let clade_size = columns.len();
+    let chain: Vec<Genome> = leaves.iter().filter_map(|l| match &l.vertex {
+        TreeVertex::Floor(f) => f.first().cloned(),
+        TreeVertex::Split(_) => None,
+    }).collect();
+    columns.extend(chain.iter().cloned());
+    let distances = refine::distance_rows(&chain, &columns, model, metadata)?;
+
+    // stack the levels back on top of the clade, closest to it first
+    let groups = chain_groups(&distances, clade_size);
+    let mut leaves: Vec<Option<TreeNode>> = leaves.into_iter().map(Some).collect();
+    let mut cur = core;
+    for level in chain_levels(&distances, clade_size, &groups, options.tolerance) {
+        let mut split = TreeNode::new_with_floor(0, 0);
+        split.vertex = TreeVertex::new_split();
+        split.vertex.push_node(cur);
+        for group in level {
+            let mut members: Vec<TreeNode> = groups[group].iter().map(|i| leaves[*i].take().unwrap()).collect();
+            if members.len() == 1 {
+                split.vertex.push_node(members.pop().unwrap());
+                continue;
+            }
+            let mut floor = TreeNode::new_with_floor(0, 0); //genomes closer to each other than to the clade share a floor
+            floor.vertex = TreeVertex::Floor(members.into_iter().flat_map(|m| match m.vertex {
+                TreeVertex::Floor(f) => f,
+                TreeVertex::Split(_) => Vec::new(),
+            }).collect());
+            split.vertex.push_node(floor);
+        }
+        cur = split;
+    }
+    *node = cur;
+    Ok(count)
+}
+
+
+/// Group the genomes of a chain that are closer to each other than either of them is to the clade below
+///
+/// Every row holds the distances from one genome of the chain to the genomes of the clade, then to every
+/// genome of the chain in the same order. Groups are listed by their first genome, as are their genomes.
+fn chain_groups(distances: &[Vec<f64>], clade_size: usize) -> Vec<Vec<usize>> {
+    let to_clade: Vec<f64> = distances.iter().map(|row| row[..clade_size].iter().sum::<f64>() / clade_size as f64).collect();
+    let mut first: Vec<usize> = (0..distances.len()).collect(); //first genome of the group every genome is in
+    for i in 0..distances.len() {
+        for j in i+1..distances.len() {
+            if distances[i][clade_size + j] < to_clade[i].min(to_clade[j]) && first[i] != first[j] {
+                let (keep, merged) = (first[i].min(first[j]), first[i].max(first[j]));
+                first.iter_mut().filter(|f| **f == merged).for_each(|f| *f = keep);
+            }
+        }
+    }
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (i, f) in first.into_iter().enumerate() {
+        groups.entry(f).or_default().push(i);
+    }
+    groups.into_values().collect()
+}
+
+
+/// Order the groups of a chain into the levels stacked back onto the clade, closest to it first
+///
+/// Every level takes the group with the smallest mean distance to the genomes already in the subtree,
+/// along with every group within the tolerance of that distance. A clade without genomes measures
+/// nothing, so then every group shares one level.
+fn chain_levels(distances: &[Vec<f64>], clade_size: usize, groups: &[Vec<usize>], tolerance: f64) -> Vec<Vec<usize>> {
+    let mut inside: Vec<bool> = (0..clade_size + distances.len()).map(|j| j < clade_size).collect();
+    let mut remaining: Vec<usize> = (0..groups.len()).collect();
+    let mut ret = Vec::new();
+    while !remaining.is_empty() {
+        let means: Vec<f64> = remaining.iter().map(|g| {
+            let placed: Vec<f64> = groups[*g].iter()
+                .flat_map(|i| distances[*i].iter().zip(&inside).filter(|(_, p)| **p).map(|(d, _)| *d))
+                .collect();
+            placed.iter().sum::<f64>() / placed.len() as f64
+        }).collect();
+        let closest = means.iter().copied().fold(f64::INFINITY, f64::min);
+
+        let (mut level, mut rest) = (Vec::new(), Vec::new());
+        for (g, mean) in remaining.into_iter().zip(means) {
+            if mean.is_nan() || mean <= closest * tolerance {
+                groups[g].iter().for_each(|i| inside[clade_size + i] = true);
+                level.push(g);
+            } else {
+                rest.push(g);
+            }
+        }
+        ret.push(level);
+        remaining = rest;
+    }
+    ret
+}
+
+
+/// Number of single-genome floors hanging off the chain starting at this node
+fn chain_length(node: &TreeNode) -> usize {
+    let mut ret = 0;
+    let mut cur = node;
+    while let Some((next, _)) = chain_step(cur) {
+        ret += 1;
+        cur = next;
+    }
+    ret
+}
+
+
+/// If the node is one link of a chain, return (the next link, the single-genome floor)
+fn chain_step(node: &TreeNode) -> Option<(&TreeNode, &TreeNode)> {
+    let TreeVertex::Split(s) = &node.vertex else {
+        return None;
+    };
+    match s.as_slice() {
+        [a, b] if is_single_floor(b) && !is_single_floor(a) => Some((a, b)),
+        [a, b] if is_single_floor(a) && !is_single_floor(b) => Some((b, a)),
+        _ => None,
+    }
+}
+
+
+/// Take a chain apart, returning the clade at its bottom and its single-genome floors, top first
+fn unwind_chain(head: TreeNode) -> (TreeNode, Vec<TreeNode>) {
+    let mut leaves = Vec::new();
+    let mut cur = head;
+    loop {
+        let is_link = chain_step(&cur).is_some();
+        match cur.vertex {
+            TreeVertex::Split(s) if is_link => {
+                let mut it = s.into_iter();
+                let (mut next, mut leaf) = (it.next().unwrap(), it.next().unwrap());
+                if is_single_floor(&next) {
+                    std::mem::swap(&mut next, &mut leaf);
+                }
+                leaves.push(leaf);
+                cur = next;
+            },
+            vertex => {
+                cur.vertex = vertex;
+                return (cur, leaves);
+            }
+        }
+    }
+}
+
+
+/// Collect the genomes under a node
+fn clade_genomes(node: &TreeNode, genomes: &mut Vec<Genome>) {
+    match &node.vertex {
+        TreeVertex::Split(s) => {
+            for child in s {
+                clade_genomes(child, genomes);
+            }
+        },
+        TreeVertex::Floor(f) => genomes.extend(f.iter().cloned()),
+    }
+}
+
+
+/// Check whether a node is a floor holding exactly one genome
+fn is_single_floor(node: &TreeNode) -> bool {
+    matches!(&node.vertex, TreeVertex::Floor(f) if f.len() == 1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::parse_tree;
+
+    fn leaf(name: &str) -> TreeNode {
+        parse_tree(&format!("floor:\n    {}\n", name)).unwrap()
+    }
+
+    fn link(below: TreeNode, leaf: TreeNode) -> TreeNode {
+        let mut split = TreeNode::new_with_floor(0, 0);
+        split.vertex = TreeVertex::new_split();
+        split.vertex.push_node(below);
+        split.vertex.push_node(leaf);
+        split
+    }
+
+    /// A chain inserted as A, B then C on top of the clade (X, Y)
+    fn chain() -> TreeNode {
+        let core = parse_tree("floor:\n    X\n    Y\n").unwrap();
+        link(link(link(core, leaf("A")), leaf("B")), leaf("C"))
+    }
+
+    fn name(node: &TreeNode) -> String {
+        match &node.vertex {
+            TreeVertex::Floor(f) => String::from(f[0].name()),
+            TreeVertex::Split(_) => String::new(),
+        }
+    }
+
+    #[test]
+    fn chains_are_measured_down_to_the_clade() {
+        assert_eq!(chain_length(&chain()), 3);
+        assert_eq!(chain_length(&leaf("A")), 0);
+    }
+
+    #[test]
+    fn short_chains_are_left_alone() {
+        // a chain shorter than the minimum is never measured, so no genome files are read
+        let mut node = chain();
+        let options = RebalanceOptions { min_chain: 4, tolerance: 1.17 };
+        assert_eq!(rebalance_node(&mut node, DistanceModel::Raw, &Metadata::default(), &options).unwrap(), 0);
+        assert_eq!(chain_length(&node), 3);
+    }
+
+    #[test]
+    fn genomes_closer_to_each_other_than_to_the_clade_are_grouped() {
+        // A and B are 1 apart and 10 from the clade of X and Y, C is 2 from the clade
+        let distances = vec![
+            vec![10.0, 10.0, 0.0, 1.0, 10.0],
+            vec![10.0, 10.0, 1.0, 0.0, 10.0],
+            vec![2.0, 2.0, 10.0, 10.0, 0.0],
+        ];
+        let groups = chain_groups(&distances, 2);
+        assert_eq!(groups, vec![vec![0, 1], vec![2]]);
+        assert_eq!(chain_levels(&distances, 2, &groups, 1.17), vec![vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn near_ties_share_a_level() {
+        // A and B are about as far from the clade, and further from each other
+        let distances = vec![
+            vec![5.0, 5.0, 0.0, 20.0],
+            vec![5.5, 5.5, 20.0, 0.0],
+        ];
+        let groups = chain_groups(&distances, 2);
+        assert_eq!(groups, vec![vec![0], vec![1]]);
+        assert_eq!(chain_levels(&distances, 2, &groups, 1.17), vec![vec![0, 1]]);
+        assert_eq!(chain_levels(&distances, 2, &groups, 1.05), vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn unwinding_keeps_the_clade_at_the_bottom() {
+        let (core, leaves) = unwind_chain(chain());
+        assert!(matches!(&core.vertex, TreeVertex::Floor(f) if f.len() == 2));
+        assert_eq!(leaves.iter().map(name).collect::<Vec<_>>(), vec!["C", "B", "A"]);
+    }
+}
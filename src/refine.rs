@@ -0,0 +1,816 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, thread};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{distance::{Comparison, DistanceModel}, errors::PhyloError, metadata::Metadata, structs::{Genome, PhyloTree, TreeNode, TreeVertex}, log_debug, log_info};
+
+
+/// Score a tree is judged by, lower is better for both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    LeastSquares,       // squared differences between pairwise distances and path lengths
+    MinimumEvolution,   // total length of the tree, with lengths fitted by least squares and negative ones counted as zero
+}
+impl Objective {
+
+    /// Parse an objective from its name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ls" | "least-squares" => Some(Objective::LeastSquares),
+            "me" | "min-evolution" => Some(Objective::MinimumEvolution),
+            _ => None,
+        }
+    }
+}
+
+
+/// Settings for the rearrangement passes
+#[derive(Debug, Clone)]
+pub struct RefineOptions {
+    pub objective: Objective,   // how candidate trees are scored
+    pub nni: bool,              // try nearest neighbour interchanges
+    pub spr: bool,              // try subtree prune and regraft moves
+    pub spr_radius: usize,      // furthest a subtree may be moved, counted in edges
+    pub max_rounds: u32,        // upper bound on the number of accepted moves
+    pub model: DistanceModel,   // how the pairwise distances are measured
+}
+
+
+/// What the rearrangement passes did
+#[derive(Debug, Clone)]
+pub struct RefineSummary {
+    pub initial_score: f64,     // score of the tree as it was built
+    pub final_score: f64,       // score after the last accepted move
+    pub nni_moves: u32,         // accepted nearest neighbour interchanges
+    pub spr_moves: u32,         // accepted prune and regraft moves
+}
+
+
+/// Distance between every pair of genomes under the given model
+///
+/// Every genome is compared against every other one, so this grows with the square of the tree size.
+/// Distances are symmetric, so every pair is only measured once.
+pub fn pairwise_distances(genomes: &[Genome], model: DistanceModel, metadata: &Metadata) -> Result<Vec<Vec<f64>>, PhyloError> {
+    let flags: Vec<_> = genomes.iter().map(|g| metadata.flags(g.name())).collect();
+    let segments = genomes.iter().map(|g| g.segments()).collect::<Result<Vec<_>, PhyloError>>()?;
+
+    let n = genomes.len();
+    let upper = measure_rows(n, |i| (i+1..n).map(|j| model.segmented_distance(&segments[i], &segments[j], Comparison::between(flags[i], flags[j])).0).collect())?;
+    let mut ret = vec![vec![0.0; n]; n];
+    for (i, row) in upper.into_iter().enumerate() {
+        for (offset, distance) in row.into_iter().enumerate() {
+            ret[i][i + 1 + offset] = distance;
+            ret[i + 1 + offset][i] = distance;
+        }
+    }
+    Ok(ret)
+}
+
+
+/// Distance from every genome in the first list to every genome in the second
+///
+/// Genomes are compared segment by segment, and lined up according to their flags, as during placement.
+/// A genome is at distance zero from itself.
+pub fn distance_rows(rows: &[Genome], columns: &[Genome], model: DistanceModel, metadata: &Metadata) -> Result<Vec<Vec<f64>>, PhyloError> {
+    let row_flags: Vec<_> = rows.iter().map(|g| metadata.flags(g.name())).collect();
+    let column_flags: Vec<_> = columns.iter().map(|g| metadata.flags(g.name())).collect();
+    let row_segments = rows.iter().map(|g| g.segments()).collect::<Result<Vec<_>, PhyloError>>()?;
+    let column_segments = columns.iter().map(|g| g.segments()).collect::<Result<Vec<_>, PhyloError>>()?;
+
+    measure_rows(rows.len(), |i| (0..columns.len()).map(|j| match rows[i].dir == columns[j].dir {
+        true => 0.0,
+        false => model.segmented_distance(&row_segments[i], &column_segments[j], Comparison::between(row_flags[i], column_flags[j])).0,
+    }).collect())
+}
+
+
+/// Measure the given number of rows, shared out between a thread per core
+fn measure_rows<F: Fn(usize) -> Vec<f64> + Sync>(n: usize, measure: F) -> Result<Vec<Vec<f64>>, PhyloError> {
+    let workers = thread::available_parallelism().map(|w| w.get()).unwrap_or(1).min(n.max(1));
+    let next_row = Mutex::new(0);
+    let joined = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers).map(|_| scope.spawn(|| {
+            let mut measured = Vec::new();
+            loop {
+                let i = {
+                    let mut next = next_row.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                if i >= n {
+                    return measured;
+                }
+                measured.push((i, measure(i)));
+            }
+        })).collect();
+        handles.into_iter().map(|h| h.join()).collect::<Result<Vec<_>, _>>()
+    });
+
+    let mut ret = vec![Vec::new(); n];
+    for (i, row) in joined.map_err(|_| PhyloError::RefineError(String::from("Error joining threads after measuring distances")))?.into_iter().flatten() {
+        ret[i] = row;
+    }
+    Ok(ret)
+}
+
+
+/// Rearrange the tree with NNI and SPR moves until no move improves the score
+///
+/// Moves are tried on a flattened copy of the tree where floors are internal nodes and genomes are
+/// leaves. A move is scored by refitting only the edges whose clade it changed and the edges touching
+/// those, keeping every other length. For an NNI move on a binary tree that matches a full refit, and
+/// otherwise the squared error can only come out higher than a full refit's, so a move is never taken for
+/// a fit it doesn't have. The total length has no such bound, so under minimum evolution the best move is
+/// refitted in full before it's taken, and the search ends if it turns out no shorter.
+///
+/// If any move is accepted, the tree is rebuilt from that copy: nodes whose children are all genomes
+/// become floors, and genomes sitting next to other nodes get a floor of their own. Support values don't
+/// survive a rebuild. Either way every edge is refitted at the end.
+pub fn refine(tree: &mut PhyloTree, options: &RefineOptions) -> Result<RefineSummary, PhyloError> {
+    let mut genomes = Vec::new();
+    let mut arena = Arena::from_tree(&tree.root, &mut genomes);
+    let distances = pairwise_distances(&genomes, options.model, &tree.metadata)?;
+    let mut table = DistanceTable::new(&distances);
+
+    let mut fit = Fit::new(&arena, &mut table);
+    let initial_score = fit.score(options.objective);
+    let mut summary = RefineSummary { initial_score, final_score: initial_score, nni_moves: 0, spr_moves: 0 };
+    if genomes.len() < 4 { //every tree on three genomes has the same topology
+        return Ok(summary);
+    }
+
+    // keep taking the best move until nothing improves, trying the cheaper NNI moves first
+    let mut rounds = 0;
+    while rounds < options.max_rounds {
+        let current = fit.score(options.objective);
+        let tolerance = 1e-9 * current.abs().max(1.0);
+        let mut best: Option<(f64, Arena, Fit, bool)> = None; //(score, tree, its fit, whether it was an NNI move)
+
+        let nni = if options.nni { arena.nni_moves() } else { Vec::new() };
+        for candidate in nni {
+            let candidate_fit = fit.refit(&candidate, &mut table);
+            let score = candidate_fit.score(options.objective);
+            if score < current - tolerance && best.as_ref().map(|b| score < b.0).unwrap_or(true) {
+                best = Some((score, candidate, candidate_fit, true));
+            }
+        }
+        if best.is_none() && options.spr {
+            for candidate in arena.spr_moves(options.spr_radius) {
+                let candidate_fit = fit.refit(&candidate, &mut table);
+                let score = candidate_fit.score(options.objective);
+                if score < current - tolerance && best.as_ref().map(|b| score < b.0).unwrap_or(true) {
+                    best = Some((score, candidate, candidate_fit, false));
+                }
+            }
+        }
+
+        let Some((score, candidate, candidate_fit, is_nni)) = best else {
+            break;
+        };
+
+        // refitted lengths only bound the squared error, the total length has to be checked against a full refit
+        let (score, candidate_fit) = match options.objective {
+            Objective::LeastSquares => (score, candidate_fit),
+            Objective::MinimumEvolution => {
+                let full = Fit::new(&candidate, &mut table);
+                if full.total >= current - tolerance {
+                    log_debug!("best move only shortens the tree before a full refit, {} -> {}", current, full.total);
+                    break;
+                }
+                (full.total, full)
+            }
+        };
+        log_debug!("accepted {} move, score {} -> {}", if is_nni { "NNI" } else { "SPR" }, current, score);
+        if is_nni {
+            summary.nni_moves += 1;
+        } else {
+            summary.spr_moves += 1;
+        }
+        arena = candidate;
+        fit = candidate_fit;
+        rounds += 1;
+    }
+
+    let fit = Fit::new(&arena, &mut table);
+    summary.final_score = fit.score(options.objective);
+    arena.set_lengths(&fit.lengths);
+    if summary.nni_moves + summary.spr_moves > 0 {
+        for node in arena.nodes.iter_mut() { //the clades have changed since the support was measured
+            node.support = None;
+        }
+        tree.root = arena.to_tree(&genomes);
+        tree.next_index = tree.root.renumber(0)?;
+    } else {
+        arena.write_lengths(&mut tree.root);
+    }
+    log_info!("refinement made {} NNI and {} SPR moves, score {} -> {}", summary.nni_moves, summary.spr_moves, summary.initial_score, summary.final_score);
+    Ok(summary)
+}
+
+
+/// Node of the flattened tree
+#[derive(Debug, Clone)]
+pub struct ArenaNode {
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub genome: Option<usize>,  // index of the genome for leaves
+    pub length: Option<f64>,    // length of the edge leading into this node, if known
+    pub support: Option<f64>,   // support of the clade under this node, if measured
+}
+
+
+/// Flattened copy of a tree that's cheap to rearrange
+///
+/// Moves never renumber nodes, a node spliced out by a move is left behind without a parent, so the
+/// nodes of a tree and the trees it was rearranged into can be matched up by index.
+#[derive(Debug, Clone)]
+pub struct Arena {
+    pub nodes: Vec<ArenaNode>,
+    pub root: usize,
+}
+impl Arena {
+
+    /// Flatten a tree, collecting its genomes in the order their leaves are created
+    pub fn from_tree(root: &TreeNode, genomes: &mut Vec<Genome>) -> Self {
+        let mut arena = Arena { nodes: Vec::new(), root: 0 };
+        arena.root = arena.add_node(root, None, genomes);
+        arena
+    }
+
+    /// Internal recursive function for from_tree, a floor holding a single genome becomes that genome's leaf
+    fn add_node(&mut self, node: &TreeNode, parent: Option<usize>, genomes: &mut Vec<Genome>) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(ArenaNode { parent, children: Vec::new(), genome: None, length: node.length, support: node.support });
+        match &node.vertex {
+            TreeVertex::Split(s) => {
+                for child in s {
+                    let child_index = self.add_node(child, Some(index), genomes);
+                    self.nodes[index].children.push(child_index);
+                }
+            },
+            TreeVertex::Floor(f) if f.len() == 1 => { //the floor's edge and the genome's edge become one
+                self.nodes[index].genome = Some(genomes.len());
+                self.nodes[index].length = node.length.map(|l| l + f[0].length.unwrap_or(0.0));
+                genomes.push(f[0].clone());
+            },
+            TreeVertex::Floor(f) => {
+                for genome in f {
+                    self.nodes.push(ArenaNode { parent: Some(index), children: Vec::new(), genome: Some(genomes.len()), length: genome.length, support: None });
+                    let leaf = self.nodes.len() - 1;
+                    self.nodes[index].children.push(leaf);
+                    genomes.push(genome.clone());
+                }
+            }
+        }
+        index
+    }
+
+    /// Rebuild a tree from the flattened copy, ids and paths are left for TreeNode::renumber
+    pub fn to_tree(&self, genomes: &[Genome]) -> TreeNode {
+        self.build_node(self.root, genomes)
+    }
+
+    /// Internal recursive function for to_tree
+    fn build_node(&self, index: usize, genomes: &[Genome]) -> TreeNode {
+        let node = &self.nodes[index];
+        let mut ret = TreeNode::new_with_floor(0, 0);
+        ret.length = node.length;
+        ret.support = node.support;
+        if let Some(g) = node.genome { //a lone genome takes the whole edge, and sits right on its floor
+            let mut genome = genomes[g].clone();
+            genome.length = node.length.map(|_| 0.0);
+            ret.vertex = TreeVertex::Floor(vec![genome]);
+        } else if node.children.iter().all(|c| self.nodes[*c].genome.is_some()) {
+            ret.vertex = TreeVertex::Floor(node.children.iter().map(|c| {
+                let mut genome = genomes[self.nodes[*c].genome.unwrap()].clone();
+                genome.length = self.nodes[*c].length;
+                genome
+            }).collect());
+        } else {
+            ret.vertex = TreeVertex::new_split();
+            for child in &node.children {
+                ret.vertex.push_node(self.build_node(*child, genomes));
+            }
+        }
+        ret
+    }
+
+    /// Store fitted lengths on every node but the root, negative fits are clamped to zero
+    pub fn set_lengths(&mut self, lengths: &[f64]) {
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.length = if i == self.root { None } else { Some(lengths[i].max(0.0)) };
+        }
+    }
+
+    /// Copy the lengths back onto the tree this was flattened from, which must not have changed shape
+    pub fn write_lengths(&self, root: &mut TreeNode) {
+        let mut next = 0;
+        self.write_node(root, &mut next);
+    }
+
+    /// Internal recursive function for write_lengths, visits nodes in the same order as add_node
+    fn write_node(&self, node: &mut TreeNode, next: &mut usize) {
+        let index = *next;
+        *next += 1;
+        node.length = self.nodes[index].length;
+        match &mut node.vertex {
+            TreeVertex::Split(s) => {
+                for child in s {
+                    self.write_node(child, next);
+                }
+            },
+            TreeVertex::Floor(f) if f.len() == 1 => {
+                f[0].length = node.length.map(|_| 0.0);
+            },
+            TreeVertex::Floor(f) => {
+                for genome in f {
+                    genome.length = self.nodes[*next].length;
+                    *next += 1;
+                }
+            }
+        }
+    }
+
+    /// Whether every edge of the tree has a length
+    pub fn lengths_known(&self) -> bool {
+        self.nodes.iter().enumerate().all(|(i, n)| i == self.root || n.length.is_some())
+    }
+
+    /// Least squares lengths for the edge above every node, returns (lengths, sum of squared residuals)
+    ///
+    /// Edges that can't be told apart, such as the two edges below a root with two children, share their
+    /// length through a small ridge term.
+    pub fn fit_lengths(&self, distances: &[Vec<f64>]) -> (Vec<f64>, f64) {
+        let fit = Fit::new(self, &mut DistanceTable::new(distances));
+        (fit.lengths, fit.residual)
+    }
+
+    /// Every tree one nearest neighbour interchange away, swapping a child of a node with a sibling of that node
+    fn nni_moves(&self) -> Vec<Arena> {
+        let mut ret = Vec::new();
+        for v in 0..self.nodes.len() {
+            let Some(u) = self.nodes[v].parent else {
+                continue;
+            };
+            for &c in &self.nodes[v].children {
+                for &s in self.nodes[u].children.iter().filter(|s| **s != v) {
+                    let mut candidate = self.clone();
+                    candidate.replace_child(v, c, s);
+                    candidate.replace_child(u, s, c);
+                    ret.push(candidate);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Every tree one prune and regraft move away, within the given radius of where the subtree was
+    ///
+    /// A pruned subtree is either grafted onto the middle of an edge or added as one more child of an
+    /// internal node.
+    fn spr_moves(&self, radius: usize) -> Vec<Arena> {
+        let mut ret = Vec::new();
+        for x in 0..self.nodes.len() {
+            let Some(p) = self.nodes[x].parent else {
+                continue;
+            };
+            let inside = self.subtree(x);
+            let reach = self.edge_distances(p);
+            for y in 0..self.nodes.len() {
+                if inside[y] || y == p || reach[y] > radius {
+                    continue;
+                }
+                if let Some(candidate) = self.prune_and_regraft(x, y, false) {
+                    ret.push(candidate);
+                }
+                if self.nodes[y].genome.is_none() && self.nodes[x].parent != Some(y) {
+                    if let Some(candidate) = self.prune_and_regraft(x, y, true) {
+                        ret.push(candidate);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Move the subtree under x onto the edge above y, or under y itself when attach is set
+    fn prune_and_regraft(&self, x: usize, y: usize, attach: bool) -> Option<Arena> {
+        let mut ret = self.clone();
+        let p = ret.nodes[x].parent?;
+        ret.nodes[p].children.retain(|c| *c != x);
+
+        // a node left with a single child is spliced out
+        match ret.nodes[p].children.len() {
+            0 => return None,
+            1 => {
+                let only = ret.nodes[p].children[0];
+                match ret.nodes[p].parent {
+                    Some(g) => {
+                        ret.replace_child(g, p, only);
+                    },
+                    None => {
+                        ret.root = only;
+                        ret.nodes[only].parent = None;
+                    }
+                }
+                ret.nodes[p].children.clear();
+                ret.nodes[p].parent = None;
+                if y == p {
+                    return None;
+                }
+            },
+            _ => {}
+        }
+
+        if attach {
+            ret.nodes[y].children.push(x);
+            ret.nodes[x].parent = Some(y);
+        } else {
+            let joint = ret.nodes.len();
+            let above = ret.nodes[y].parent;
+            ret.nodes.push(ArenaNode { parent: above, children: vec![y, x], genome: None, length: None, support: None });
+            match above {
+                Some(g) => ret.replace_child(g, y, joint),
+                None => ret.root = joint,
+            }
+            ret.nodes[y].parent = Some(joint);
+            ret.nodes[x].parent = Some(joint);
+        }
+        Some(ret)
+    }
+
+    /// Replace one child of a node with another node, updating the parent link
+    fn replace_child(&mut self, node: usize, old: usize, new: usize) {
+        for child in self.nodes[node].children.iter_mut() {
+            if *child == old {
+                *child = new;
+            }
+        }
+        self.nodes[new].parent = Some(node);
+    }
+
+    /// Which nodes sit in the subtree under the given node, including itself
+    fn subtree(&self, node: usize) -> Vec<bool> {
+        let mut ret = vec![false; self.nodes.len()];
+        let mut stack = vec![node];
+        while let Some(cur) = stack.pop() {
+            ret[cur] = true;
+            stack.extend(&self.nodes[cur].children);
+        }
+        ret
+    }
+
+    /// Number of edges between the given node and every other node
+    fn edge_distances(&self, from: usize) -> Vec<usize> {
+        let mut ret = vec![usize::MAX; self.nodes.len()];
+        let mut queue = VecDeque::from([from]);
+        ret[from] = 0;
+        while let Some(cur) = queue.pop_front() {
+            let node = &self.nodes[cur];
+            for next in node.children.iter().chain(node.parent.iter()) {
+                if ret[*next] == usize::MAX {
+                    ret[*next] = ret[cur] + 1;
+                    queue.push_back(*next);
+                }
+            }
+        }
+        ret
+    }
+}
+
+
+/// Pairwise distances along with the sums every fit of them needs
+struct DistanceTable<'a> {
+    distances: &'a [Vec<f64>],
+    row_sums: Vec<f64>,             // distance from every genome to all the others
+    squares: f64,                   // sum of the squared distances over every pair
+    keys: Vec<u64>,                 // random key of every genome, a clade is known by the sum of its keys
+    crossing: HashMap<u64, f64>,    // sum of the distances between a clade and the rest, by the clade's key
+}
+impl<'a> DistanceTable<'a> {
+
+    /// Collect the sums of a distance matrix
+    fn new(distances: &'a [Vec<f64>]) -> Self {
+        let mut rng = StdRng::seed_from_u64(distances.len() as u64);
+        DistanceTable {
+            distances,
+            row_sums: distances.iter().map(|row| row.iter().sum()).collect(),
+            squares: distances.iter().enumerate().map(|(i, row)| row[i+1..].iter().map(|d| d * d).sum::<f64>()).sum(),
+            keys: distances.iter().map(|_| rng.gen()).collect(),
+            crossing: HashMap::new(),
+        }
+    }
+
+    /// Sum of the distances between the genomes under a node and every other genome, measured on the smaller side
+    fn crossing_sum(&mut self, arena: &Arena, shape: &Shape, node: usize) -> f64 {
+        if let Some(sum) = self.crossing.get(&shape.keys[node]) {
+            return *sum;
+        }
+        let (enter, exit) = (shape.enter[node], shape.exit[node]);
+        let side: Vec<usize> = match shape.sizes[node] * 2 <= self.distances.len() {
+            true => shape.order[enter..exit].iter().filter_map(|v| arena.nodes[*v].genome).collect(),
+            false => shape.order[..enter].iter().chain(&shape.order[exit..]).filter_map(|v| arena.nodes[*v].genome).collect(),
+        };
+        let within: f64 = side.iter().enumerate().map(|(a, i)| side[a+1..].iter().map(|j| self.distances[*i][*j]).sum::<f64>()).sum();
+        let sum = side.iter().map(|i| self.row_sums[*i]).sum::<f64>() - 2.0 * within;
+        self.crossing.insert(shape.keys[node], sum);
+        sum
+    }
+}
+
+
+/// Layout of a flattened tree as seen from its root
+#[derive(Debug, Clone)]
+struct Shape {
+    root: usize,
+    order: Vec<usize>,  // every node reachable from the root, in preorder
+    enter: Vec<usize>,  // position of every node in the preorder, usize::MAX for nodes left behind by a move
+    exit: Vec<usize>,   // position just past the last node under every node
+    sizes: Vec<usize>,  // number of genomes under every node
+    keys: Vec<u64>,     // sum of the keys of the genomes under every node
+    genomes: usize,     // number of genomes in the tree
+}
+impl Shape {
+
+    /// Lay out a tree, the keys of its genomes come from the distance table
+    fn of(arena: &Arena, keys: &[u64]) -> Self {
+        let nodes = arena.nodes.len();
+        let mut ret = Shape { root: arena.root, order: Vec::with_capacity(nodes), enter: vec![usize::MAX; nodes], exit: vec![0; nodes], sizes: vec![0; nodes], keys: vec![0; nodes], genomes: keys.len() };
+        let mut stack = vec![arena.root];
+        while let Some(cur) = stack.pop() {
+            ret.enter[cur] = ret.order.len();
+            ret.order.push(cur);
+            stack.extend(arena.nodes[cur].children.iter().rev());
+        }
+
+        // sizes and keys build up from the leaves, children come after their parent in the preorder
+        for &cur in ret.order.iter().rev() {
+            ret.exit[cur] = arena.nodes[cur].children.iter().map(|c| ret.exit[*c]).max().unwrap_or(ret.enter[cur] + 1).max(ret.enter[cur] + 1);
+            if let Some(g) = arena.nodes[cur].genome {
+                ret.sizes[cur] += 1;
+                ret.keys[cur] = ret.keys[cur].wrapping_add(keys[g]);
+            }
+            if let Some(p) = arena.nodes[cur].parent {
+                ret.sizes[p] += ret.sizes[cur];
+                ret.keys[p] = ret.keys[p].wrapping_add(ret.keys[cur]);
+            }
+        }
+        ret
+    }
+
+    /// Whether there's an edge above the node, which holds for every node reachable from the root but the root
+    fn has_edge(&self, node: usize) -> bool {
+        node < self.enter.len() && self.enter[node] != usize::MAX && node != self.root
+    }
+
+    /// Number of pairs of genomes whose path crosses both the edge above u and the edge above v
+    fn shared_pairs(&self, u: usize, v: usize) -> f64 {
+        if !self.has_edge(u) || !self.has_edge(v) {
+            return 0.0;
+        }
+        let (su, sv, n) = (self.sizes[u], self.sizes[v], self.genomes);
+        let pairs = if u == v {
+            su * (n - su)
+        } else if self.enter[u] < self.enter[v] && self.enter[v] < self.exit[u] { //v sits under u
+            sv * (n - su)
+        } else if self.enter[v] < self.enter[u] && self.enter[u] < self.exit[v] { //u sits under v
+            su * (n - sv)
+        } else {
+            su * sv
+        };
+        pairs as f64
+    }
+}
+
+
+/// Least squares lengths of a flattened tree, with what's needed to score a move away from it
+///
+/// Pairs of genomes are never listed: the pairs whose path crosses two edges follow from the sizes of
+/// the clades under them, and the distances crossing an edge are summed once per clade, so the squared
+/// error is sum(d^2) - 2 sum(l * crossing) + sum over pairs of edges of l * l' * shared pairs.
+#[derive(Debug, Clone)]
+struct Fit {
+    shape: Shape,
+    lengths: Vec<f64>,      // fitted length of the edge above every node, zero where there's no edge
+    crossing: Vec<f64>,     // sum of the distances crossing the edge above every node
+    residual: f64,          // sum of squared differences between the distances and the fitted path lengths
+    total: f64,             // sum of the lengths, with negative ones counted as zero
+}
+impl Fit {
+
+    /// Fit every edge of the tree at once
+    fn new(arena: &Arena, table: &mut DistanceTable) -> Self {
+        let shape = Shape::of(arena, &table.keys);
+        let edges: Vec<usize> = shape.order.iter().copied().filter(|v| shape.has_edge(*v)).collect();
+        let mut crossing = vec![0.0; arena.nodes.len()];
+        for &v in &edges {
+            crossing[v] = table.crossing_sum(arena, &shape, v);
+        }
+
+        let fitted = Self::solve_edges(&shape, &edges, edges.iter().map(|v| crossing[*v]).collect());
+        let mut lengths = vec![0.0; arena.nodes.len()];
+        for (v, l) in edges.iter().zip(fitted) {
+            lengths[*v] = l;
+        }
+        let linear: f64 = edges.iter().map(|v| lengths[*v] * crossing[*v]).sum();
+        let quadratic: f64 = edges.iter().map(|u| lengths[*u] * edges.iter().map(|v| lengths[*v] * shape.shared_pairs(*u, *v)).sum::<f64>()).sum();
+        Fit {
+            residual: (table.squares - 2.0 * linear + quadratic).max(0.0),
+            total: lengths.iter().map(|l| l.max(0.0)).sum(),
+            shape,
+            lengths,
+            crossing,
+        }
+    }
+
+    /// Fit a tree a move away from this one, refitting the edges whose clade changed and the edges touching them
+    fn refit(&self, candidate: &Arena, table: &mut DistanceTable) -> Self {
+        let old = &self.shape;
+        let shape = Shape::of(candidate, &table.keys);
+        let nodes = candidate.nodes.len();
+        let changed = |v: usize| shape.has_edge(v) && (!old.has_edge(v) || old.keys[v] != shape.keys[v]);
+
+        // free edges are refitted, every other edge keeps its length unless the move took it away
+        let mut free = vec![false; nodes];
+        for &v in shape.order.iter().filter(|v| changed(**v)) {
+            let node = &candidate.nodes[v];
+            let siblings = node.parent.map(|p| candidate.nodes[p].children.as_slice()).unwrap_or_default();
+            for u in node.children.iter().chain(node.parent.iter()).chain(siblings).chain([&v]) {
+                free[*u] = shape.has_edge(*u);
+            }
+        }
+        let differs: Vec<usize> = (0..nodes).filter(|v| free[*v] || (old.has_edge(*v) && !shape.has_edge(*v))).collect();
+        let free_edges: Vec<usize> = differs.iter().copied().filter(|v| free[*v]).collect();
+
+        let mut crossing = self.crossing.clone();
+        crossing.resize(nodes, 0.0);
+        for &v in &differs {
+            crossing[v] = match (changed(v), shape.has_edge(v)) {
+                (true, _) => table.crossing_sum(candidate, &shape, v),
+                (false, true) => self.crossing[v],
+                (false, false) => 0.0,
+            };
+        }
+
+        // the kept edges account for part of every distance crossing a free edge
+        let kept: Vec<usize> = shape.order.iter().copied().filter(|v| shape.has_edge(*v) && !free[*v]).collect();
+        let targets = free_edges.iter().map(|e| crossing[*e] - kept.iter().map(|g| shape.shared_pairs(*e, *g) * self.lengths[*g]).sum::<f64>()).collect();
+        let fitted = Self::solve_edges(&shape, &free_edges, targets);
+        let mut lengths = self.lengths.clone();
+        lengths.resize(nodes, 0.0);
+        for &v in &differs {
+            lengths[v] = 0.0;
+        }
+        for (v, l) in free_edges.iter().zip(fitted) {
+            lengths[*v] = l;
+        }
+
+        // only terms that involve an edge whose length or clade changed can differ from this fit
+        let old_length = |v: usize| self.lengths.get(v).copied().unwrap_or(0.0);
+        let old_crossing = |v: usize| self.crossing.get(v).copied().unwrap_or(0.0);
+        let linear: f64 = differs.iter().map(|v| lengths[*v] * crossing[*v] - old_length(*v) * old_crossing(*v)).sum();
+        let mut quadratic = 0.0;
+        for &u in &differs {
+            for &v in &differs {
+                quadratic += lengths[u] * lengths[v] * shape.shared_pairs(u, v) - old_length(u) * old_length(v) * old.shared_pairs(u, v);
+            }
+            for &v in &kept {
+                quadratic += 2.0 * lengths[v] * (lengths[u] * shape.shared_pairs(u, v) - old_length(u) * old.shared_pairs(u, v));
+            }
+        }
+        let total_change: f64 = differs.iter().map(|v| lengths[*v].max(0.0) - old_length(*v).max(0.0)).sum();
+
+        Fit {
+            residual: (self.residual - 2.0 * linear + quadratic).max(0.0),
+            total: self.total + total_change,
+            shape,
+            lengths,
+            crossing,
+        }
+    }
+
+    /// Least squares lengths for the given edges from what the distances crossing each of them leave to explain
+    ///
+    /// Edges that can't be told apart, such as the two edges below a root with two children, share their
+    /// length through a small ridge term.
+    fn solve_edges(shape: &Shape, edges: &[usize], targets: Vec<f64>) -> Vec<f64> {
+        let n = shape.genomes;
+        let ridge = 1e-9 * (1.0 + (n * n / 4) as f64);
+        let m = edges.iter().map(|u| edges.iter().map(|v| shape.shared_pairs(*u, *v) + if u == v { ridge } else { 0.0 }).collect()).collect();
+        solve(m, targets)
+    }
+
+    /// How the tree scores under the given objective, lower is better for both
+    fn score(&self, objective: Objective) -> f64 {
+        match objective {
+            Objective::LeastSquares => self.residual,
+            Objective::MinimumEvolution => self.total,
+        }
+    }
+}
+
+
+/// Solve a linear system with Gaussian elimination and partial pivoting
+fn solve(mut m: Vec<Vec<f64>>, mut b: Vec<f64>) -> Vec<f64> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|x, y| m[*x][col].abs().total_cmp(&m[*y][col].abs())).unwrap();
+        m.swap(col, pivot);
+        b.swap(col, pivot);
+        if m[col][col].abs() < f64::EPSILON {
+            continue;
+        }
+        for row in col+1..n {
+            let factor = m[row][col] / m[col][col];
+            if factor == 0.0 {
+                continue;
+            }
+            let (upper, lower) = m.split_at_mut(row);
+            for (target, source) in lower[0][col..].iter_mut().zip(&upper[col][col..]) {
+                *target -= factor * source;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut ret = vec![0.0; n];
+    for row in (0..n).rev() {
+        if m[row][row].abs() < f64::EPSILON {
+            continue;
+        }
+        let known: f64 = (row+1..n).map(|k| m[row][k] * ret[k]).sum();
+        ret[row] = (b[row] - known) / m[row][row];
+    }
+    ret
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::parse_tree;
+
+    fn arena(text: &str) -> Arena {
+        Arena::from_tree(&parse_tree(text).unwrap(), &mut Vec::new())
+    }
+
+    #[test]
+    fn additive_distances_are_fitted_exactly() {
+        // ((A:1,B:2):3,C:4,D:5)
+        let tree = arena("split:\n    split:\n        floor:\n            A\n        floor:\n            B\n    floor:\n        C\n    floor:\n        D\n");
+        let distances = vec![
+            vec![0.0, 3.0, 8.0, 9.0],
+            vec![3.0, 0.0, 9.0, 10.0],
+            vec![8.0, 9.0, 0.0, 9.0],
+            vec![9.0, 10.0, 9.0, 0.0],
+        ];
+        let (lengths, residual) = tree.fit_lengths(&distances);
+        assert!(residual.abs() < 1e-6);
+        for (i, node) in tree.nodes.iter().enumerate() {
+            let expected = match node.genome {
+                Some(g) => [1.0, 2.0, 4.0, 5.0][g],
+                None if i == tree.root => continue,
+                None => 3.0,
+            };
+            assert!((lengths[i] - expected).abs() < 1e-6, "edge {} is {}", i, lengths[i]);
+        }
+    }
+
+    #[test]
+    fn nni_swaps_children_with_siblings() {
+        // the inner node above A and B can trade either of them for C or D
+        let tree = arena("split:\n    split:\n        floor:\n            A\n        floor:\n            B\n    floor:\n        C\n    floor:\n        D\n");
+        assert_eq!(tree.nni_moves().len(), 4);
+    }
+
+    #[test]
+    fn nni_refits_match_full_fits() {
+        let tree = arena("split:
+    split:
+        floor:
+            A
+        floor:
+            B
+    split:
+        floor:
+            C
+        floor:
+            D
+    split:
+        floor:
+            E
+        floor:
+            F
+");
+        let distances: Vec<Vec<f64>> = (0..6).map(|i| (0..6).map(|j| if i == j { 0.0 } else { 1.0 + ((i + 1) * (j + 1) % 7) as f64 }).collect()).collect();
+        let mut table = DistanceTable::new(&distances);
+        let fit = Fit::new(&tree, &mut table);
+
+        let moves = tree.nni_moves();
+        assert!(!moves.is_empty());
+        for candidate in moves {
+            let local = fit.refit(&candidate, &mut table).residual;
+            let full = Fit::new(&candidate, &mut table).residual;
+            assert!((local - full).abs() < 1e-6 * full.max(1.0), "refit {} against {}", local, full);
+        }
+    }
+}
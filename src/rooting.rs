@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+
+use crate::{errors::PhyloError, refine::{Arena, ArenaNode}, structs::{Genome, PhyloTree}, log_info};
+
+
+impl PhyloTree {
+
+    /// Root the tree halfway along the longest path between two genomes
+    ///
+    /// Uses the branch lengths of the tree, estimating them first if any are missing.
+    pub fn root_midpoint(&mut self) -> Result<(), PhyloError> {
+        let (genomes, unrooted) = self.unrooted(true)?;
+        let leaves = unrooted.leaves();
+
+        // find the two genomes furthest apart
+        let mut best = (0.0, leaves[0], leaves[0]);
+        for &leaf in &leaves {
+            let (dist, _) = unrooted.distances_from(leaf);
+            for &other in &leaves {
+                if dist[other] > best.0 {
+                    best = (dist[other], leaf, other);
+                }
+            }
+        }
+        let (length, from, to) = best;
+
+        // walk back from the far genome until we pass the middle of the path
+        let (dist, towards) = unrooted.distances_from(from);
+        let mut cur = to;
+        while let Some(next) = towards[cur] {
+            if dist[next] <= length / 2.0 {
+                log_info!("midpoint root placed on a path of length {:.3}", length);
+                return self.apply_root(&unrooted, &genomes, next, cur, Some(length / 2.0 - dist[next]));
+            }
+            cur = next;
+        }
+        Err(PhyloError::RootError(String::from("tree needs at least two genomes to be rooted")))
+    }
+
+    /// Root the tree on the edge separating the named genomes from the rest
+    ///
+    /// A single name roots the tree on the branch leading to that genome, several names need to form a
+    /// clade once the current root is ignored.
+    pub fn root_outgroup(&mut self, names: &[&str]) -> Result<(), PhyloError> {
+        let (genomes, unrooted) = self.unrooted(false)?;
+
+        // find the leaves of the outgroup
+        let mut outgroup = HashSet::new();
+        for name in names {
+            let leaf = unrooted.leaves().into_iter()
+                .find(|l| unrooted.genome[*l].map(|g| genomes[g].name() == *name).unwrap_or(false))
+                .ok_or(PhyloError::RootError(format!("no genome named {} in the tree", name)))?;
+            outgroup.insert(leaf);
+        }
+        if outgroup.is_empty() || outgroup.len() == unrooted.leaves().len() {
+            return Err(PhyloError::RootError(String::from("the outgroup must hold some but not all of the genomes")));
+        }
+
+        // find an edge with exactly the outgroup on one side
+        for (a, b, _) in unrooted.edges() {
+            let side: HashSet<usize> = unrooted.side(a, b).into_iter().filter(|n| unrooted.genome[*n].is_some()).collect();
+            if side == outgroup || (side.len() + outgroup.len() == unrooted.leaves().len() && side.is_disjoint(&outgroup)) {
+                return self.apply_root(&unrooted, &genomes, a, b, None);
+            }
+        }
+        Err(PhyloError::RootError(format!("{} don't form a clade in the tree", names.join(", "))))
+    }
+
+    /// Root the tree where the variance of the distances from the root to every genome is smallest
+    ///
+    /// Uses the branch lengths of the tree, estimating them first if any are missing.
+    pub fn root_min_variance(&mut self) -> Result<(), PhyloError> {
+        let (genomes, unrooted) = self.unrooted(true)?;
+        let leaves = unrooted.leaves();
+        let mut best: Option<(f64, usize, usize, f64)> = None; //(variance, a, b, distance of the root from a)
+
+        for (a, b, length) in unrooted.edges() {
+            // placing the root t along the edge from a moves every genome on a's side t further away,
+            // and every genome on b's side t closer, so the variance is a quadratic in t
+            let b_side: HashSet<usize> = unrooted.side(a, b).into_iter().collect();
+            let (dist_a, _) = unrooted.distances_from(a);
+            let (dist_b, _) = unrooted.distances_from(b);
+            let points: Vec<(f64, f64)> = leaves.iter() //(distance with the root at a, direction)
+                .map(|l| if b_side.contains(l) { (dist_b[*l] + length, -1.0) } else { (dist_a[*l], 1.0) })
+                .collect();
+
+            let n = points.len() as f64;
+            let mean_c = points.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_s = points.iter().map(|p| p.1).sum::<f64>() / n;
+            let mean_cs = points.iter().map(|p| p.0 * p.1).sum::<f64>() / n;
+            let mean_cc = points.iter().map(|p| p.0 * p.0).sum::<f64>() / n;
+            let square = 1.0 - mean_s * mean_s;
+            let linear = 2.0 * (mean_cs - mean_c * mean_s);
+            let constant = mean_cc - mean_c * mean_c;
+
+            let t = if square > 0.0 { (-linear / (2.0 * square)).clamp(0.0, length) } else { 0.0 };
+            let variance = square * t * t + linear * t + constant;
+            if best.map(|(v, _, _, _)| variance < v).unwrap_or(true) {
+                best = Some((variance, a, b, t));
+            }
+        }
+
+        let (variance, a, b, t) = best.ok_or(PhyloError::RootError(String::from("tree needs at least two genomes to be rooted")))?;
+        log_info!("minimum variance root found with a variance of {:.3}", variance);
+        self.apply_root(&unrooted, &genomes, a, b, Some(t))
+    }
+
+    /// Flatten the tree and drop its root, estimating branch lengths first if they're needed and missing
+    fn unrooted(&mut self, with_lengths: bool) -> Result<(Vec<Genome>, Unrooted), PhyloError> {
+        let mut genomes = Vec::new();
+        let mut arena = Arena::from_tree(&self.root, &mut genomes);
+        if genomes.len() < 2 {
+            return Err(PhyloError::RootError(String::from("tree needs at least two genomes to be rooted")));
+        }
+        if with_lengths && !arena.lengths_known() {
+            self.estimate_lengths()?;
+            genomes.clear();
+            arena = Arena::from_tree(&self.root, &mut genomes);
+        }
+        Ok((genomes, Unrooted::from_arena(&arena)))
+    }
+
+    /// Replace the tree with the one rooted on the edge between a and b, at the given distance from a or halfway
+    ///
+    /// Support values stay with the edge they were measured on, whichever way round it ends up, and the
+    /// edge the root splits in two gives its support to both halves.
+    fn apply_root(&mut self, unrooted: &Unrooted, genomes: &[Genome], a: usize, b: usize, from_a: Option<f64>) -> Result<(), PhyloError> {
+        self.root = unrooted.root_on(a, b, from_a).to_tree(genomes);
+        self.next_index = self.root.renumber(0)?;
+        Ok(())
+    }
+}
+
+
+/// One end of an edge, (node at the other end, length, support)
+type Edge = (usize, Option<f64>, Option<f64>);
+
+
+/// Unrooted view of a flattened tree, every edge stored at both ends along with its length and support
+struct Unrooted {
+    adjacency: Vec<Vec<Edge>>,
+    genome: Vec<Option<usize>>,
+}
+impl Unrooted {
+
+    /// Build the unrooted view, merging the two edges below a root with two children into one
+    fn from_arena(arena: &Arena) -> Self {
+        let mut adjacency = vec![Vec::new(); arena.nodes.len()];
+        for (i, node) in arena.nodes.iter().enumerate() {
+            if let Some(p) = node.parent {
+                adjacency[i].push((p, node.length, node.support));
+                adjacency[p].push((i, node.length, node.support));
+            }
+        }
+
+        let root = arena.root;
+        if let [(a, la, sa), (b, lb, sb)] = adjacency[root][..] {
+            let merged = la.zip(lb).map(|(x, y)| x + y);
+            let support = sa.or(sb); //both halves hold the same split
+            adjacency[root].clear();
+            adjacency[a].retain(|e| e.0 != root);
+            adjacency[b].retain(|e| e.0 != root);
+            adjacency[a].push((b, merged, support));
+            adjacency[b].push((a, merged, support));
+        }
+        Unrooted { adjacency, genome: arena.nodes.iter().map(|n| n.genome).collect() }
+    }
+
+    /// Every node holding a genome
+    fn leaves(&self) -> Vec<usize> {
+        (0..self.genome.len()).filter(|i| self.genome[*i].is_some()).collect()
+    }
+
+    /// Every edge once, as (a, b, length), with missing lengths read as zero
+    fn edges(&self) -> Vec<(usize, usize, f64)> {
+        let mut ret = Vec::new();
+        for (a, edges) in self.adjacency.iter().enumerate() {
+            for (b, length, _) in edges {
+                if a < *b {
+                    ret.push((a, *b, length.unwrap_or(0.0)));
+                }
+            }
+        }
+        ret
+    }
+
+    /// Distance from the start to every node, along with the next node on the way back to the start
+    fn distances_from(&self, start: usize) -> (Vec<f64>, Vec<Option<usize>>) {
+        let mut dist = vec![f64::INFINITY; self.adjacency.len()];
+        let mut towards = vec![None; self.adjacency.len()];
+        let mut stack = vec![start];
+        dist[start] = 0.0;
+        while let Some(cur) = stack.pop() {
+            for (next, length, _) in &self.adjacency[cur] {
+                if dist[*next] == f64::INFINITY {
+                    dist[*next] = dist[cur] + length.unwrap_or(0.0);
+                    towards[*next] = Some(cur);
+                    stack.push(*next);
+                }
+            }
+        }
+        (dist, towards)
+    }
+
+    /// The nodes on b's side of the edge between a and b
+    fn side(&self, a: usize, b: usize) -> Vec<usize> {
+        let mut ret = vec![b];
+        let mut stack = vec![(b, a)];
+        while let Some((cur, from)) = stack.pop() {
+            for (next, _, _) in &self.adjacency[cur] {
+                if *next != from {
+                    ret.push(*next);
+                    stack.push((*next, cur));
+                }
+            }
+        }
+        ret
+    }
+
+    /// Flatten the tree again with a new root on the edge between a and b, at the given distance from a or halfway
+    fn root_on(&self, a: usize, b: usize, from_a: Option<f64>) -> Arena {
+        let (length, support) = self.adjacency[a].iter().find(|e| e.0 == b).map(|e| (e.1, e.2)).unwrap_or_default();
+        let from_a = length.map(|l| from_a.unwrap_or(l / 2.0).clamp(0.0, l));
+        let mut ret = Arena { nodes: vec![ArenaNode { parent: None, children: Vec::new(), genome: None, length: None, support: None }], root: 0 };
+        for (node, other, edge) in [(a, b, from_a), (b, a, length.zip(from_a).map(|(l, t)| l - t))] {
+            let child = self.copy_node(&mut ret, node, other, 0, edge, support);
+            ret.nodes[0].children.push(child);
+        }
+        ret
+    }
+
+    /// Internal recursive function for root_on, copies the node and everything away from where it was reached
+    fn copy_node(&self, arena: &mut Arena, node: usize, from: usize, parent: usize, length: Option<f64>, support: Option<f64>) -> usize {
+        let index = arena.nodes.len();
+        arena.nodes.push(ArenaNode { parent: Some(parent), children: Vec::new(), genome: self.genome[node], length, support });
+        for (next, edge, edge_support) in &self.adjacency[node] {
+            if *next != from {
+                let child = self.copy_node(arena, *next, node, index, *edge, *edge_support);
+                arena.nodes[index].children.push(child);
+            }
+        }
+        index
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{distance::DistanceModel, input::parse_tree, structs::{TreeNode, TreeVertex}};
+
+    /// A, B, C and D hanging off a chain, edges of length 1 except the one leading to D, the clade of C and D has support
+    const CHAIN: &str = "split:
+    floor: length=1
+        A length=0
+    split: length=1
+        floor: length=1
+            B length=0
+        split: 0.75 length=1
+            floor: length=1
+                C length=0
+            floor: length=5
+                D length=0
+";
+
+    fn tree(text: &str) -> PhyloTree {
+        let mut ret = PhyloTree::new(DistanceModel::Raw);
+        ret.root = parse_tree(text).unwrap();
+        ret
+    }
+
+    /// Distance from the root to every genome
+    fn depths(node: &TreeNode, above: f64, ret: &mut HashMap<String, f64>) {
+        let here = above + node.length.unwrap_or(0.0);
+        match &node.vertex {
+            TreeVertex::Split(s) => s.iter().for_each(|c| depths(c, here, ret)),
+            TreeVertex::Floor(f) => ret.extend(f.iter().map(|g| (String::from(g.name()), here + g.length.unwrap_or(0.0)))),
+        }
+    }
+
+    /// Support of the node holding exactly the named genomes
+    fn clade_support(node: &TreeNode, names: &[&str]) -> Option<Option<f64>> {
+        let mut below = HashMap::new();
+        depths(node, 0.0, &mut below);
+        if below.len() == names.len() && names.iter().all(|n| below.contains_key(*n)) {
+            return Some(node.support);
+        }
+        match &node.vertex {
+            TreeVertex::Split(s) => s.iter().find_map(|c| clade_support(c, names)),
+            TreeVertex::Floor(_) => None,
+        }
+    }
+
+    #[test]
+    fn midpoint_splits_the_longest_path() {
+        // A to D is 8 long, so the root sits 1 along the edge from C and D's node to D
+        let mut chain = tree(CHAIN);
+        chain.root_midpoint().unwrap();
+        let mut found = HashMap::new();
+        depths(&chain.root, 0.0, &mut found);
+        for (name, depth) in [("A", 4.0), ("B", 3.0), ("C", 2.0), ("D", 4.0)] {
+            assert!((found[name] - depth).abs() < 1e-9, "{} is {} from the root", name, found[name]);
+        }
+    }
+
+    #[test]
+    fn outgroup_keeps_support_on_its_edge() {
+        // the edge above C and D separates them from A and B, which hang below it once D is the outgroup
+        let mut chain = tree(CHAIN);
+        chain.root_outgroup(&["D"]).unwrap();
+        let TreeVertex::Split(s) = &chain.root.vertex else { panic!("the root should be a split") };
+        assert_eq!(s.len(), 2);
+        assert!(s.iter().any(|c| matches!(&c.vertex, TreeVertex::Floor(f) if f.len() == 1 && f[0].name() == "D")));
+        assert_eq!(clade_support(&chain.root, &["A", "B"]), Some(Some(0.75)));
+    }
+
+    #[test]
+    fn outgroup_must_be_a_clade_of_known_genomes() {
+        assert!(tree(CHAIN).root_outgroup(&["A", "C"]).is_err());
+        assert!(tree(CHAIN).root_outgroup(&["E"]).is_err());
+        assert!(tree(CHAIN).root_outgroup(&["A", "B", "C", "D"]).is_err());
+    }
+
+    #[test]
+    fn min_variance_evens_out_depths() {
+        // two cherries 4 apart, every genome ends up 3 from the root halfway between them
+        let cherries = "split:
+    split: length=1
+        floor: length=1
+            A length=0
+        floor: length=1
+            B length=0
+    split: length=3
+        floor: length=1
+            C length=0
+        floor: length=1
+            D length=0
+";
+        let mut cherries = tree(cherries);
+        cherries.root_min_variance().unwrap();
+        let mut found = HashMap::new();
+        depths(&cherries.root, 0.0, &mut found);
+        assert!(found.values().all(|d| (d - 3.0).abs() < 1e-9), "{:?}", found);
+    }
+}
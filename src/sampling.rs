@@ -0,0 +1,225 @@
+use rand::Rng;
+
+use crate::errors::PhyloError;
+
+
+/// Decides whether a drawn unit of weight is put back before the next draw
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    With,       // every draw sees the original weights
+    Without,    // every draw consumes one unit of weight from the chosen element
+}
+
+
+/// Weighted sampler over a fixed list of elements
+///
+/// Drawing with replacement uses Vose's alias method (O(n) setup, O(1) per draw), while drawing
+/// without replacement uses a Fenwick tree over the weights (O(n) setup, O(log n) per draw). The alias
+/// table is built once with the sampler, since the weights it draws from never change, only the Fenwick
+/// tree is rebuilt for every batch of draws that consumes weight.
+#[derive(Debug, Clone)]
+pub struct WeightedSampler {
+    elems: Vec<u32>,        // the elements being sampled
+    weights: Vec<u32>,      // the weight of each element, matches elems by index
+    total: u64,             // the sum of all weights
+    table: AliasTable,      // alias table over the weights, for draws with replacement
+}
+impl WeightedSampler {
+
+    /// Create a new sampler, fails if the lengths don't match or if there is no weight to sample
+    pub fn new(elems: Vec<u32>, weights: Vec<u32>) -> Result<Self, PhyloError> {
+        if elems.len() != weights.len() {
+            return Err(PhyloError::SamplingError(format!("received {} elements but {} weights", elems.len(), weights.len())));
+        }
+        let total = weights.iter().map(|w| *w as u64).sum();
+        if total == 0 {
+            return Err(PhyloError::SamplingError(String::from("total weight is zero")));
+        }
+        let table = AliasTable::new(&weights);
+        Ok(WeightedSampler { elems, weights, total, table })
+    }
+
+    /// Draw the given number of elements
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R, rounds: u32, replacement: Replacement) -> Result<Vec<u32>, PhyloError> {
+        match replacement {
+            Replacement::With => Ok((0..rounds).map(|_| self.elems[self.table.draw(rng)]).collect()),
+            Replacement::Without => {
+                if rounds as u64 > self.total { //every draw consumes a unit of weight, so we can't draw more than we have
+                    return Err(PhyloError::SamplingError(format!("requested {} draws without replacement but only {} units of weight exist", rounds, self.total)));
+                }
+                let mut tree = FenwickTree::new(&self.weights);
+                let mut ret = Vec::with_capacity(rounds as usize);
+                for _ in 0..rounds {
+                    let index = tree.find(rng.gen_range(0..tree.total));
+                    tree.decrement(index);
+                    ret.push(self.elems[index]);
+                }
+                Ok(ret)
+            },
+        }
+    }
+}
+
+
+/// Alias table used for constant time draws with replacement
+#[derive(Debug, Clone)]
+struct AliasTable {
+    prob: Vec<f64>,     // the chance of keeping the column's own index
+    alias: Vec<usize>,  // the index to fall back to otherwise
+}
+impl AliasTable {
+
+    /// Build the table with Vose's method
+    fn new(weights: &[u32]) -> Self {
+        let n = weights.len();
+        let total: u64 = weights.iter().map(|w| *w as u64).sum();
+        let mut scaled: Vec<f64> = weights.iter().map(|w| *w as f64 * n as f64 / total as f64).collect();
+        let mut prob = vec![0.0; n];
+        let mut alias = vec![0; n];
+
+        // split the columns into those under and over the average
+        let mut small: Vec<usize> = Vec::new();
+        let mut large: Vec<usize> = Vec::new();
+        for (i, p) in scaled.iter().enumerate() {
+            if *p < 1.0 {
+                small.push(i);
+            } else {
+                large.push(i);
+            }
+        }
+
+        // fill each small column with the excess of a large one
+        while !small.is_empty() && !large.is_empty() {
+            let (s, l) = (small.pop().unwrap(), large.pop().unwrap());
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] = (scaled[l] + scaled[s]) - 1.0;
+            if scaled[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+
+        // anything left over is full, up to floating point error
+        for i in large.into_iter().chain(small) {
+            prob[i] = 1.0;
+        }
+
+        AliasTable { prob, alias }
+    }
+
+    /// Draw a single index
+    fn draw<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        let column = rng.gen_range(0..self.prob.len());
+        if rng.gen::<f64>() < self.prob[column] {
+            column
+        } else {
+            self.alias[column]
+        }
+    }
+}
+
+
+/// Fenwick (binary indexed) tree of weights used for draws without replacement
+#[derive(Debug, Clone)]
+struct FenwickTree {
+    tree: Vec<u64>,     // 1-indexed partial sums
+    total: u64,         // the sum of all remaining weights
+}
+impl FenwickTree {
+
+    /// Build the tree in linear time
+    fn new(weights: &[u32]) -> Self {
+        let mut tree = vec![0u64; weights.len() + 1];
+        for (i, w) in weights.iter().enumerate() {
+            tree[i + 1] += *w as u64;
+            let parent = (i + 1) + ((i + 1) & (i + 1).wrapping_neg());
+            if parent < tree.len() {
+                tree[parent] += tree[i + 1];
+            }
+        }
+        let total = weights.iter().map(|w| *w as u64).sum();
+        FenwickTree { tree, total }
+    }
+
+    /// Find the index whose cumulative weight range contains the target
+    fn find(&self, mut target: u64) -> usize {
+        let mut pos = 0;
+        let mut step = (self.tree.len() - 1).next_power_of_two();
+        while step > 0 {
+            if pos + step < self.tree.len() && self.tree[pos + step] <= target {
+                pos += step;
+                target -= self.tree[pos];
+            }
+            step >>= 1;
+        }
+        pos //pos is the last 1-indexed position at or below the target, so it's the 0-indexed answer
+    }
+
+    /// Remove one unit of weight from the given index
+    fn decrement(&mut self, index: usize) {
+        let mut i = index + 1;
+        while i < self.tree.len() {
+            self.tree[i] -= 1;
+            i += i & i.wrapping_neg();
+        }
+        self.total -= 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn draws_with_replacement_follow_the_weights() {
+        let sampler = WeightedSampler::new(vec![10, 20, 30], vec![1, 0, 3]).unwrap();
+        let draws = sampler.sample(&mut StdRng::seed_from_u64(0), 4000, Replacement::With).unwrap();
+        assert!(!draws.contains(&20)); //zero weights are never drawn
+        let thirties = draws.iter().filter(|d| **d == 30).count();
+        assert!((2800..3200).contains(&thirties), "drew 30 {} times out of 4000", thirties);
+    }
+
+    #[test]
+    fn draws_without_replacement_use_up_the_weights() {
+        let sampler = WeightedSampler::new(vec![10, 20, 30], vec![2, 0, 1]).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut draws = sampler.sample(&mut rng, 3, Replacement::Without).unwrap();
+        draws.sort();
+        assert_eq!(draws, vec![10, 10, 30]);
+        assert!(sampler.sample(&mut rng, 4, Replacement::Without).is_err()); //only 3 units of weight exist
+    }
+
+    #[test]
+    fn samplers_need_weight() {
+        assert!(WeightedSampler::new(vec![1, 2], vec![1]).is_err());
+        assert!(WeightedSampler::new(vec![1, 2], vec![0, 0]).is_err());
+        assert!(WeightedSampler::new(Vec::new(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn alias_table_columns() {
+        // scaled to [0.5, 1.5], the first column is topped up by the second
+        let table = AliasTable::new(&[1, 3]);
+        assert_eq!(table.prob, vec![0.5, 1.0]);
+        assert_eq!(table.alias[0], 1);
+        assert!(AliasTable::new(&[2, 2, 2]).prob.iter().all(|p| *p == 1.0));
+    }
+
+    #[test]
+    fn fenwick_tree_finds_and_decrements() {
+        // cumulative ranges 0..3, 3..3, 3..5 and 5..10
+        let mut tree = FenwickTree::new(&[3, 0, 2, 5]);
+        assert_eq!(tree.total, 10);
+        assert_eq!([0, 2, 3, 4, 5, 9].map(|t| tree.find(t)), [0, 0, 2, 2, 3, 3]);
+        for _ in 0..3 {
+            tree.decrement(0);
+        }
+        assert_eq!(tree.total, 7);
+        assert_eq!(tree.find(0), 2);
+    }
+}
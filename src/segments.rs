@@ -0,0 +1,67 @@
+use std::fs;
+
+use crate::{bases, errors::PhyloError};
+
+
+/// One named sequence of a genome, such as a segment of a segmented virus
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub name: String,       // "segment 4" when the header says so, otherwise the accession
+    pub sequence: Vec<u8>,  // bases without line breaks
+}
+
+
+/// Read the segments of a genome file
+pub fn read_segments(file_dir: &str) -> Result<Vec<Segment>, PhyloError> {
+    let contents = fs::read_to_string(file_dir).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))?;
+    Ok(parse_segments(&contents))
+}
+
+
+/// Split a genome file into segments, every '>' header line starts a new one
+///
+/// Files written before headers were kept hold a single unnamed sequence, which becomes one segment
+/// called "genome". Bases are normalized as they're read, see `bases::normalize`.
+pub fn parse_segments(contents: &str) -> Vec<Segment> {
+    let mut ret: Vec<Segment> = Vec::new();
+    for line in contents.lines() {
+        if let Some(header) = line.strip_prefix('>') {
+            ret.push(Segment { name: segment_name(header), sequence: Vec::new() });
+            continue;
+        }
+        if ret.is_empty() {
+            ret.push(Segment { name: String::from("genome"), sequence: Vec::new() });
+        }
+        ret.last_mut().unwrap().sequence.extend(line.bytes().filter(|b| !b.is_ascii_whitespace()).map(bases::normalize));
+    }
+    ret.retain(|s| !s.sequence.is_empty());
+    ret
+}
+
+
+/// Every base of the genome in one sequence, segments laid end to end
+pub fn read_sequence(file_dir: &str) -> Result<Vec<u8>, PhyloError> {
+    Ok(read_segments(file_dir)?.into_iter().flat_map(|s| s.sequence).collect())
+}
+
+
+/// The segments of a genome as one string with a line break between segments, for kmer searches
+///
+/// Kmers never hold a line break, so they can't match across the border of two segments.
+pub fn sequence_text(file_dir: &str) -> Result<String, PhyloError> {
+    let segments = read_segments(file_dir)?;
+    let joined: Vec<u8> = segments.iter().map(|s| s.sequence.as_slice()).collect::<Vec<_>>().join(&b'\n');
+    String::from_utf8(joined).map_err(|_| PhyloError::FileReadError(String::from(file_dir)))
+}
+
+
+/// Short name of a segment taken from its header line
+fn segment_name(header: &str) -> String {
+    if let Some(loc) = header.find("segment ") {
+        let number = header[loc+8..].split([' ', ',']).next().unwrap_or("");
+        if !number.is_empty() {
+            return format!("segment {}", number);
+        }
+    }
+    String::from(header.split_whitespace().next().unwrap_or(header))
+}
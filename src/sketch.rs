@@ -0,0 +1,166 @@
+use std::cmp::Ordering;
+
+use crate::{algorithms::{self, KmerOptions}, bases, errors::PhyloError, segments::{self, Segment}};
+
+
+/// FracMinHash sketch of a genome: the hash of every kmer that falls in a fixed fraction of the hash space
+///
+/// Unlike a sketch keeping a fixed number of the lowest hashes, the number of hashes grows with the
+/// genome, so a 2 kb virus and a 5 Mb bacterium are both sketched at the same resolution and the
+/// containment of one in the other can be estimated directly. Kmers are read on whichever strand gives
+/// the lower sequence, so genomes deposited on opposite strands still share their hashes.
+#[derive(Debug, Clone, Default)]
+pub struct Sketch {
+    pub k: usize,           // length of every kmer
+    pub scaled: u64,        // one in this many hashes is kept, on average
+    pub hashes: Vec<u64>,   // kept hashes, sorted and distinct
+}
+impl Sketch {
+
+    /// Sketch a genome file
+    pub fn read(file_dir: &str, options: &KmerOptions) -> Result<Self, PhyloError> {
+        Ok(Self::from_segments(&segments::read_segments(file_dir)?, options))
+    }
+
+    /// Sketch every segment of a genome, skipping kmers that hold an ambiguity code
+    pub fn from_segments(segments: &[Segment], options: &KmerOptions) -> Self {
+        let (k, scaled) = (options.sketch_k as usize, options.scaled.max(1) as u64);
+        let max_hash = u64::MAX / scaled;
+        let mut hashes = Vec::new();
+        let mut reverse = vec![0; k]; //reverse complement of the current kmer, reused between kmers
+        for segment in segments {
+            for kmer in segment.sequence.windows(k) {
+                if kmer.iter().any(|b| bases::is_ambiguous(*b)) {
+                    continue;
+                }
+                for (r, b) in reverse.iter_mut().zip(kmer.iter().rev()) {
+                    *r = bases::complement(*b);
+                }
+                let hash = algorithms::kmer_hash(kmer.min(&reverse[..]));
+                if hash <= max_hash {
+                    hashes.push(hash);
+                }
+            }
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        Sketch { k, scaled, hashes }
+    }
+
+    /// Largest hash both sketches keep, sketches made at different scales are compared at the coarser one
+    fn common_max(&self, other: &Sketch) -> u64 {
+        u64::MAX / self.scaled.max(other.scaled).max(1)
+    }
+
+    /// Number of hashes found in both sketches
+    pub fn shared(&self, other: &Sketch) -> usize {
+        let max_hash = self.common_max(other);
+        let (mut i, mut j, mut ret) = (0, 0, 0);
+        while i < self.hashes.len() && j < other.hashes.len() && self.hashes[i] <= max_hash && other.hashes[j] <= max_hash {
+            match self.hashes[i].cmp(&other.hashes[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    ret += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Number of hashes this sketch keeps when compared with the other
+    pub fn comparable(&self, other: &Sketch) -> usize {
+        let max_hash = self.common_max(other);
+        self.hashes.partition_point(|h| *h <= max_hash)
+    }
+
+    /// Estimated fraction of this genome's kmers found in the other genome
+    pub fn containment(&self, other: &Sketch) -> f64 {
+        let total = self.comparable(other);
+        if total == 0 { 0.0 } else { self.shared(other) as f64 / total as f64 }
+    }
+
+    /// Average nucleotide identity between the part of this genome found in the other and the other
+    ///
+    /// A kmer survives only if all of its k bases do, so the containment is about the identity to the
+    /// power of k. This assumes all of this genome is in the other, parts that are missing lower it.
+    pub fn ani(&self, other: &Sketch) -> f64 {
+        let containment = self.containment(other);
+        if containment == 0.0 { 0.0 } else { containment.powf(1.0 / self.k as f64) }
+    }
+}
+
+
+/// A reference genome found in a sample
+#[derive(Debug, Clone)]
+pub struct Containment {
+    pub genome: String,     // name of the reference
+    pub shared: usize,      // hashes of the reference found in the sample
+    pub hashes: usize,      // hashes in the sketch of the reference, at the scale of the comparison
+    pub containment: f64,   // estimated fraction of the reference's kmers found in the sample
+    pub ani: f64,           // estimated identity between the reference and its match in the sample
+}
+
+
+/// List the references the sample contains at least the given fraction of, most contained first
+pub fn contained_references(sample: &Sketch, references: &[(String, &Sketch)], min_containment: f64) -> Vec<Containment> {
+    let mut ret: Vec<Containment> = references.iter()
+        .map(|(name, sketch)| Containment {
+            genome: name.clone(),
+            shared: sketch.shared(sample),
+            hashes: sketch.comparable(sample),
+            containment: sketch.containment(sample),
+            ani: sketch.ani(sample),
+        })
+        .filter(|c| c.shared > 0 && c.containment >= min_containment)
+        .collect();
+    ret.sort_by(|a, b| b.containment.total_cmp(&a.containment).then(a.genome.cmp(&b.genome)));
+    ret
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(scaled: u64, hashes: &[u64]) -> Sketch {
+        Sketch { k: 21, scaled, hashes: hashes.to_vec() }
+    }
+
+    #[test]
+    fn identical_and_disjoint_sketches() {
+        let first = sketch(1, &[1, 2, 3, 4]);
+        assert_eq!(first.shared(&first), 4);
+        assert_eq!(first.containment(&first), 1.0);
+        assert_eq!(first.ani(&first), 1.0);
+
+        let second = sketch(1, &[5, 6]);
+        assert_eq!(first.shared(&second), 0);
+        assert_eq!(first.containment(&second), 0.0);
+        assert_eq!(first.ani(&second), 0.0);
+    }
+
+    #[test]
+    fn different_scales_compare_at_the_coarser_one() {
+        // the last hash of the fine sketch is past what the coarse one keeps, so it isn't counted
+        let fine = sketch(1, &[1, 5, u64::MAX - 1]);
+        let coarse = sketch(2, &[1, 5]);
+        assert_eq!(fine.comparable(&coarse), 2);
+        assert_eq!(fine.shared(&coarse), 2);
+        assert_eq!(fine.containment(&coarse), 1.0);
+        assert_eq!(coarse.containment(&fine), 1.0);
+    }
+
+    #[test]
+    fn both_strands_give_the_same_sketch() {
+        let options = KmerOptions { sketch_k: 5, scaled: 1, ..KmerOptions::default() };
+        let forward = Segment { name: String::from("1"), sequence: b"GCTAAAGACAATTACATAACNATACACG".to_vec() };
+        let reverse = Segment { name: String::from("1"), sequence: forward.sequence.iter().rev().map(|b| bases::complement(*b)).collect() };
+        let first = Sketch::from_segments(&[forward], &options);
+        let second = Sketch::from_segments(&[reverse], &options);
+        assert!(!first.hashes.is_empty());
+        assert_eq!(first.hashes, second.hashes);
+    }
+}
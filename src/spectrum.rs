@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use crate::{algorithms::{self, KmerOptions}, bases, errors::PhyloError, segments::{self, Segment}};
+
+
+/// How the descent scores how similar a candidate is to the genome being placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmerSimilarity {
+    Sampled,        // number of the candidate's sampled kmers found in the genome
+    Jaccard,        // shared distinct kmers over the distinct kmers of either genome
+    Containment,    // fraction of the genome's distinct kmers found in the candidate
+    Cosine,         // cosine between the kmer counts of the two genomes
+    Sketch,         // containment of the genome in the candidate, estimated from their FracMinHash sketches
+}
+impl KmerSimilarity {
+
+    /// Parse a similarity name as given on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sampled" => Some(KmerSimilarity::Sampled),
+            "jaccard" => Some(KmerSimilarity::Jaccard),
+            "containment" => Some(KmerSimilarity::Containment),
+            "cosine" => Some(KmerSimilarity::Cosine),
+            "sketch" => Some(KmerSimilarity::Sketch),
+            _ => None,
+        }
+    }
+}
+
+
+/// Every kmer of a genome along with how often it occurs
+///
+/// Only kmers made of A, C, G and T are counted, and the regions kmer sampling skips are skipped here as
+/// well, so a genome's repeats don't dominate its counts.
+#[derive(Debug, Clone, Default)]
+pub struct KmerSpectrum {
+    counts: HashMap<Vec<u8>, u32>,  // occurrences of every distinct kmer
+}
+impl KmerSpectrum {
+
+    /// Count the kmers of a genome file
+    pub fn read(file_dir: &str, options: &KmerOptions) -> Result<Self, PhyloError> {
+        Ok(Self::from_segments(&segments::read_segments(file_dir)?, options))
+    }
+
+    /// Count the kmers of every segment, never across the border of two segments
+    pub fn from_segments(segments: &[Segment], options: &KmerOptions) -> Self {
+        let k = options.k as usize;
+        let mut counts = HashMap::new();
+        for (segment, offset, count) in algorithms::kmer_stretches(segments, k, options.skip_n, options.mask) {
+            if count == 0 {
+                continue;
+            }
+            for kmer in segments[segment].sequence[offset..offset+count+k-1].windows(k) {
+                if !kmer.iter().any(|b| bases::is_ambiguous(*b)) {
+                    *counts.entry(kmer.to_vec()).or_insert(0) += 1;
+                }
+            }
+        }
+        KmerSpectrum { counts }
+    }
+
+    /// Number of distinct kmers
+    pub fn distinct(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// How often the kmer occurs
+    pub fn count(&self, kmer: &[u8]) -> u32 {
+        self.counts.get(kmer).copied().unwrap_or(0)
+    }
+
+    /// Number of distinct kmers found in both spectra
+    pub fn shared(&self, other: &KmerSpectrum) -> usize {
+        let (small, large) = if self.distinct() <= other.distinct() { (self, other) } else { (other, self) };
+        small.counts.keys().filter(|kmer| large.counts.contains_key(*kmer)).count()
+    }
+
+    /// Shared distinct kmers over the distinct kmers found in either spectrum
+    pub fn jaccard(&self, other: &KmerSpectrum) -> f64 {
+        let shared = self.shared(other);
+        let union = self.distinct() + other.distinct() - shared;
+        if union == 0 { 0.0 } else { shared as f64 / union as f64 }
+    }
+
+    /// Fraction of this spectrum's distinct kmers found in the other, close to 1 when this genome is contained in the other
+    pub fn containment(&self, other: &KmerSpectrum) -> f64 {
+        if self.distinct() == 0 { 0.0 } else { self.shared(other) as f64 / self.distinct() as f64 }
+    }
+
+    /// Cosine of the angle between the two count vectors
+    pub fn cosine(&self, other: &KmerSpectrum) -> f64 {
+        let dot: f64 = self.counts.iter().map(|(kmer, c)| *c as f64 * other.count(kmer) as f64).sum();
+        let norm = |s: &KmerSpectrum| s.counts.values().map(|c| (*c as f64).powi(2)).sum::<f64>().sqrt();
+        let norms = norm(self) * norm(other);
+        if norms == 0.0 { 0.0 } else { dot / norms }
+    }
+}
@@ -1,6 +1,7 @@
-use std::{thread, fs, sync::{Arc, Mutex}};
+use std::{thread, fs, path::Path, sync::{Arc, Mutex}, collections::{BinaryHeap, HashMap}};
+use rand::Rng;
 
-use crate::{errors::PhyloError, algorithms::{self, retrieve_genome}};
+use crate::{errors::PhyloError, algorithms::{self, retrieve_genome, KmerOptions}, distance::{Comparison, DistanceModel, SegmentMatch}, input, logging, metadata::Metadata, placement::{DescentStep, Neighbour, PlacementCase, PlacementRecord, SegmentPlacement}, refine::{self, Arena}, segments::{self, Segment}, sketch::Sketch, spectrum::{KmerSimilarity, KmerSpectrum}, log_debug, log_error, log_info, log_trace};
 
 
 /// Establishes the structure of our phylogenetic tree
@@ -9,53 +10,115 @@ pub struct TreeNode {
     pub id: u8,             // unique identifier used for finding genome paths
     pub count: u32,         // the total count of genomes under this node 
     pub vertex: TreeVertex, // decides the structure of this node
+    pub support: Option<f64>, // fraction of resampled trees that recover this node's clade, if measured
+    pub length: Option<f64>,  // length of the edge leading into this node, if estimated
 }
 impl TreeNode {
 
-    /// Initializes a new TreeNode with a TreeVertex::Split
+    // Initializes a new TreeNode with a TreeVertex::Split
     //pub fn new_with_split(id: u8, count: u32) -> Self {
     //    TreeNode { id: id, vertex: TreeVertex::Split(Vec::new()), count: count }
     //}
 
     /// Initializes a new TreeNode with a TreeVertex::Floor
     pub fn new_with_floor(id: u8, count: u32) -> Self {
-        TreeNode { id: id, vertex: TreeVertex::Floor(Vec::new()), count: count }
+        TreeNode { id, vertex: TreeVertex::Floor(Vec::new()), count, support: None, length: None }
     }
 
     /// If we have a floor, we'll switch to a split where one of the children is our current floor
     pub fn split(&mut self, id: u8) {
-        dbg!("IN THE SPLIT FUNCTION");
+        log_trace!("splitting node {} into a split with child {}", self.id, id);
         if let TreeVertex::Floor(_) = &mut self.vertex {
             let mut floor_node = TreeNode::new_with_floor(id, self.count); //the new node that will point to our current floor
             let cur_floor = std::mem::replace(&mut self.vertex, TreeVertex::new_split()); //retrieve the current floor
             floor_node.vertex = cur_floor; //place the floor into the node
             self.vertex.push_node(floor_node); //push the newly created node containing our old floor into our split
         } else {
-            println!("THIS SHOULDNT HAVE HAPPENED, FOUND A SPLIT AND NOT A FLOOR");
+            log_error!("tried to split node {} but it is already a split", self.id);
         }
     }
 
-    pub fn find<'s>(&'s self, number_heads: u32) -> Vec<&'s Genome> {
+    /// Give every node below this one a fresh id and recompute genome paths and counts, returns the next free id
+    ///
+    /// Used after the tree has been built or rearranged by something other than PhyloTree::push. Fails if
+    /// the tree has more nodes than ids can tell apart.
+    pub fn renumber(&mut self, first_id: u8) -> Result<u8, PhyloError> {
+        let mut next_id = first_id;
+        self.renumber_recursive(&mut next_id, &mut Vec::new())?;
+        Ok(next_id)
+    }
+
+    /// Internal recursive function for renumber
+    fn renumber_recursive(&mut self, next_id: &mut u8, prefix: &mut Vec<u8>) -> Result<(), PhyloError> {
+        self.id = *next_id;
+        *next_id = next_id.checked_add(1).ok_or(PhyloError::PathError(String::from("too many nodes to fit in a tree")))?;
+        prefix.push(self.id);
+        match &mut self.vertex {
+            TreeVertex::Split(s) => {
+                self.count = 0;
+                for child in s {
+                    child.renumber_recursive(next_id, prefix)?;
+                    self.count += child.count;
+                }
+            },
+            TreeVertex::Floor(f) => {
+                for (i, genome) in f.iter_mut().enumerate() {
+                    let mut path = prefix.clone();
+                    path.push(i as u8);
+                    genome.path = path;
+                }
+                self.count = f.len() as u32;
+            }
+        }
+        prefix.pop();
+        Ok(())
+    }
+
+    /// Take the genome whose file is the given one out of the tree, dropping any node left empty
+    ///
+    /// Ids, paths and counts are stale afterwards, so the tree should be renumbered.
+    pub fn remove_genome(&mut self, file_path: &Path) -> Option<Genome> {
+        let same_file = |g: &Genome| fs::canonicalize(&g.dir).map(|p| p == file_path).unwrap_or(false);
+        match &mut self.vertex {
+            TreeVertex::Floor(f) => f.iter().position(same_file).map(|i| f.remove(i)),
+            TreeVertex::Split(s) => {
+                let (i, removed) = s.iter_mut().enumerate().find_map(|(i, child)| child.remove_genome(file_path).map(|g| (i, g)))?;
+                if s[i].is_empty() {
+                    s.remove(i);
+                }
+                Some(removed)
+            }
+        }
+    }
+
+    /// Whether no genome sits under this node
+    fn is_empty(&self) -> bool {
+        match &self.vertex {
+            TreeVertex::Floor(f) => f.is_empty(),
+            TreeVertex::Split(s) => s.iter().all(TreeNode::is_empty),
+        }
+    }
+
+    /// Retrieve up to the given number of genomes under this node, spreading the heads across branches by weight
+    pub fn find<R: Rng + ?Sized>(&self, number_heads: u32, rng: &mut R) -> Result<Vec<&Genome>, PhyloError> {
         /* First we want to find 8 genomes to compare to, if available */
 
         let mut heads: Vec<(&TreeNode, u32)> = Vec::new(); //keep track of all heads (ref, heads)
-        let mut genomes: Vec<&Genome> = Vec::with_capacity(number_heads.try_into().unwrap()); //result
+        let mut genomes: Vec<&Genome> = Vec::with_capacity(number_heads as usize); //result
         heads.push((self, number_heads)); //push the root as the first head
 
-        let mut thread_rng = rand::thread_rng();
-
         // Find all the genomes to run the kmer check on
-        while heads.len() > 0 {
+        while !heads.is_empty() {
 
             let mut new_heads: Vec<(&TreeNode, u32)> = Vec::new(); //create new vector to replace current one
 
             // Repeat once per tuple in the current heads
-            for i in 0..heads.len() {
+            for head in &heads {
 
-                let mut tup = heads[i]; //get the current tuple of information
+                let mut tup = *head; //get the current tuple of information
 
                 // if the TreeNode has fewer genomes than we have heads
-                if &tup.0.count < &tup.1 {
+                if tup.0.count < tup.1 {
                     tup.1 = tup.0.count; //reduce the number of heads
                 }
 
@@ -65,27 +128,21 @@ impl TreeNode {
                     TreeVertex::Split(nodes) => { //if we have more splits
 
                         // for each node, allocate a certain number of heads to it
-                        let count = nodes.len().clone(); //number of nodes on this floor
-                        let mut weights: Vec<u32> = Vec::new(); //keep track of how many genomes each node has
-                        let mut indices: Vec<u32> = Vec::new(); //indices
-
-                        // prepare the weights, iterate for each node in the floor
                         // this sets the weight of each node as the number of genomes it holds
-                        for i in 0u32..(count as u32) {
-                            weights.push(nodes[i as usize].count);
-                            indices.push(i);
-                        }
+                        let weights: Vec<u32> = nodes.iter().map(|n| n.count).collect();
+                        let indices: Vec<u32> = (0..nodes.len() as u32).collect();
 
                         // get all the branches our heads will go to
-                        let weight_results = algorithms::random_weighted(indices, weights, tup.1, false);
-                        let branches = algorithms::vec_to_dict(weight_results);
+                        let weight_results = algorithms::random_weighted(indices, weights, tup.1, false, rng)?;
+                        let mut branches: Vec<(u32, u32)> = algorithms::vec_to_dict(weight_results).into_iter().collect();
+                        branches.sort_unstable(); //the same draws always give the same order
                        
                         // iterate through all the branches that will receive heads
-                        for branch_index in branches.keys() {
+                        for (branch_index, branch_heads) in branches {
 
                             // push the new head to the list of heads
-                            let node_ref: &TreeNode = &nodes[*branch_index as usize]; //retrieve a reference to the next node
-                            new_heads.push((node_ref, branches[branch_index]));
+                            let node_ref: &TreeNode = &nodes[branch_index as usize]; //retrieve a reference to the next node
+                            new_heads.push((node_ref, branch_heads));
                         }
 
                     },
@@ -94,7 +151,7 @@ impl TreeNode {
                         //heads.remove(i);
 
                         use rand::seq::SliceRandom;
-                        genomes.extend(f.choose_multiple(&mut thread_rng, tup.1 as _)); //chooses tup.1 (heads count) amount of genomes without repetition
+                        genomes.extend(f.choose_multiple(rng, tup.1 as _)); //chooses tup.1 (heads count) amount of genomes without repetition
                         
                         break;
                     }
@@ -102,7 +159,7 @@ impl TreeNode {
             }
             heads = new_heads;
         }
-        genomes        
+        Ok(genomes)
     }
 }
 
@@ -120,7 +177,7 @@ impl TreeVertex {
         TreeVertex::Split(Vec::new())
     }
 
-    /// Initializes a new TreeVertex floor
+    // Initializes a new TreeVertex floor
     //pub fn new_floor() -> Self {
     //    TreeVertex::Floor(Vec::new())
     //}
@@ -147,7 +204,71 @@ pub struct Genome {
     pub path: Vec<u8>,              // the path to reach this genome
     pub dir: String,                // the directory of the genome
     pub kmers: Vec<String>,         // the list of kmers for this genome
-    pub closest_distance: usize,      // Levenshtein distance between this genome and its closest relative
+    pub sketch: Sketch,             // FracMinHash sketch of the whole genome, empty unless the descent compares sketches
+    pub spectrum: KmerSpectrum,     // every kmer of the genome with its count, empty unless the descent compares whole spectra
+    pub closest_distance: f64,      // distance between this genome and its closest relative, infinite if unknown
+    pub length: Option<f64>,        // length of the edge between this genome and its floor, if estimated
+}
+impl Genome {
+
+    /// Read a genome file and prepare it for the tree, sampling its kmers as the options say
+    ///
+    /// The sketch and the full kmer spectrum are only made when the options compare them during the descent.
+    pub fn load(file_path: &str, options: &KmerOptions) -> Result<Self, PhyloError> {
+        let kmers = algorithms::generate_kmers(file_path, options)?;
+        let sketch = match options.similarity {
+            KmerSimilarity::Sketch => Sketch::read(file_path, options)?,
+            _ => Sketch::default(),
+        };
+        let spectrum = match options.similarity {
+            KmerSimilarity::Jaccard | KmerSimilarity::Containment | KmerSimilarity::Cosine => KmerSpectrum::read(file_path, options)?,
+            KmerSimilarity::Sampled | KmerSimilarity::Sketch => KmerSpectrum::default(),
+        };
+        Ok(Genome {
+            path: Vec::new(),
+            dir: String::from(file_path),
+            kmers,
+            sketch,
+            spectrum,
+            closest_distance: 0.0,
+            length: None,
+        })
+    }
+
+    /// Read the named segments of the genome from its file
+    pub fn segments(&self) -> Result<Vec<Segment>, PhyloError> {
+        segments::read_segments(&self.dir)
+    }
+
+    /// The name of the genome, taken from the folder holding its file
+    pub fn name(&self) -> &str {
+        genome_name(&self.dir)
+    }
+}
+
+
+/// Name of the genome stored at the given path, which is the directory holding its file
+pub fn genome_name(file_dir: &str) -> &str {
+    let trimmed = match file_dir.rfind('/') { //filter out the file name
+        Some(loc) => &file_dir[..loc],
+        None => return file_dir,
+    };
+    match trimmed.rfind('/') { //filter out the upper directories
+        Some(loc) => &trimmed[loc+1..],
+        None => trimmed,
+    }
+}
+
+
+/// Distances paired with the path and name of the genome they were measured against, and how every segment matched
+type DistanceList = Vec<(f64, Vec<u8>, String, Vec<SegmentMatch>)>;
+
+
+/// The genomes reached by walking down the tree, along with how we got there
+struct Descent<'a> {
+    candidates: Vec<&'a Genome>,    // the final genomes to run the full comparison against
+    steps: Vec<DescentStep>,        // every round of the descent
+    final_node: u8,                 // the node the descent stopped at
 }
 
 
@@ -156,36 +277,87 @@ pub struct Genome {
 pub struct PhyloTree {
     pub root: TreeNode,
     pub next_index: u8,     // used to decide the next TreeNode id
+    pub model: DistanceModel, // how the distance between two genomes is measured
+    pub metadata: Metadata, // flags of the genomes, partial ones are compared by local alignment
+    pub kmers: KmerOptions, // how the kmers of every genome are sampled, queries should be loaded the same way
 }
 impl PhyloTree {
 
-    /// Create a new phylogenetic tree
-    pub fn new() -> Self {
-        PhyloTree { root: TreeNode::new_with_floor(0, 0), next_index: 1 }
+    /// Create a new phylogenetic tree that measures distances with the given model
+    pub fn new(model: DistanceModel) -> Self {
+        PhyloTree { root: TreeNode::new_with_floor(0, 0), next_index: 1, model, metadata: Metadata::default(), kmers: KmerOptions::default() }
     }
 
-    /// Push a new genome onto the tree
-    pub fn push(&mut self, mut genome: Genome) -> Result<(), PhyloError>{
-        println!("======== PUSHING A NEW GENOME NOW ========");
+    /// Build a tree out of the given genome files, returning the tree and a placement record per genome
+    pub fn from_files(files: &[String], model: DistanceModel, kmers: &KmerOptions, metadata: &Metadata) -> Result<(Self, Vec<PlacementRecord>), PhyloError> {
+        let mut tree = PhyloTree::new(model);
+        tree.metadata = metadata.clone();
+        tree.kmers = *kmers;
+        let mut records = Vec::new();
 
-        let root_count_increment: bool;
+        for file_path in files {
+            let genome = Genome::load(file_path, kmers)?;
 
-        // if we have an empty tree, just push it
-        if let TreeVertex::Floor(s) = &mut self.root.vertex {
-            genome.path = vec![0, 0];
-            genome.closest_distance = std::usize::MAX;
-            if s.len() == 0 {
-                println!("---> CASE 0: First genome, push to top floor");
-                s.push(genome);
-                self.root.count = 1;
-                return Ok(());
+            log_debug!("total genomes before pushing: {}", tree.root.count);
+            let record = tree.push(genome)?;
+            log_trace!("tree after pushing: {:#?}", tree);
+            records.push(record);
+        }
+        Ok((tree, records))
+    }
+
+    /// Load a tree written by a previous build, along with the closest distances its placement report records
+    ///
+    /// Only the shape and genome names are kept in the tree file, so every genome file is read again to
+    /// sample its kmers, which is far cheaper than placing every genome again. The kmer options should be
+    /// the ones the tree was built with. The genome with the given file, if any, is left out of the tree,
+    /// so a genome already in the tree can be compared against the others.
+    pub fn load(tree_file: &str, report_file: &str, model: DistanceModel, kmers: &KmerOptions, metadata: &Metadata, exclude: Option<&str>) -> Result<Self, PhyloError> {
+        let mut root = input::read_tree(tree_file)?;
+        if let Some(exclude) = exclude.and_then(|e| fs::canonicalize(e).ok()) {
+            if root.remove_genome(&exclude).is_some() {
+                log_debug!("left {} out of the loaded tree", exclude.display());
             }
-            root_count_increment = true;
-        } else {
-            root_count_increment = false;
         }
+        load_genomes(&mut root, kmers, &input::read_closest_distances(report_file)?)?;
+        let next_index = root.renumber(0)?;
+        Ok(PhyloTree { root, next_index, model, metadata: metadata.clone(), kmers: *kmers })
+    }
+
+    /// Fit the length of every edge to the pairwise distances between genomes by least squares
+    ///
+    /// Returns the sum of squared differences between the distances and the fitted path lengths. The two
+    /// edges below a root with two children can't be told apart, so they're given similar lengths.
+    pub fn estimate_lengths(&mut self) -> Result<f64, PhyloError> {
+        let mut genomes = Vec::new();
+        let mut arena = Arena::from_tree(&self.root, &mut genomes);
+        let (lengths, residual) = arena.fit_lengths(&refine::pairwise_distances(&genomes, self.model, &self.metadata)?);
+        arena.set_lengths(&lengths);
+        arena.write_lengths(&mut self.root);
+        log_info!("estimated branch lengths, squared error {:.3}", residual);
+        Ok(residual)
+    }
+
+    /// How similar a candidate is to the genome being placed, as the kmer options ask
+    ///
+    /// The genome being placed and the candidates all carry their spectra, counted when they were loaded,
+    /// just like their sampled kmers and sketches.
+    fn descent_similarity(&self, candidate: &Genome, genome: &Genome) -> f64 {
+        match self.kmers.similarity {
+            KmerSimilarity::Sketch => genome.sketch.containment(&candidate.sketch),
+            KmerSimilarity::Jaccard => genome.spectrum.jaccard(&candidate.spectrum),
+            KmerSimilarity::Containment => genome.spectrum.containment(&candidate.spectrum),
+            KmerSimilarity::Cosine => genome.spectrum.cosine(&candidate.spectrum),
+            KmerSimilarity::Sampled => algorithms::kmer_similarity(candidate, genome) as f64,
+        }
+    }
+
+    /// Walk down the tree, narrowing in on the final set of genomes to compare the given genome against
+    fn descend(&self, genome: &Genome) -> Result<Descent<'_>, PhyloError> {
+        let mut steps: Vec<DescentStep> = Vec::new(); //every round of the descent, for the placement record
 
         // prepare variables that will be updated each iteration
+        let mut rng = algorithms::genome_rng(self.kmers.seed, genome.name()); //the genomes sampled along the way
         let mut checked: Vec<u8> = Vec::new(); //the paths we've checked so far
         let mut num_checked: u32; //the number of nodes we're checking this iteration
         let mut cur = &self.root; //the node we're checking next
@@ -195,7 +367,7 @@ impl PhyloTree {
         // find the next set of 8 nodes in this loop
         'main_loop: loop {
             // retrieve the genomes for this node
-            genomes = cur.find(8); //retrieve a random set of 8 genomes
+            genomes = cur.find(8, &mut rng)?; //retrieve a random set of 8 genomes
             num_checked = cur.count; //update the number of genomes we've looked over
 
             // decide if we exit or do another iteration ======= THIS IS WHERE WE DECIDE WHETHER TO START THE INSERTION STEP =======
@@ -207,9 +379,16 @@ impl PhyloTree {
                 // for each genome, calculate the kmer similarity
                 let mut distances = Vec::new();
                 for cur_genome in &genomes {
-                    distances.push((algorithms::kmer_similarity(*cur_genome, &genome), *cur_genome));
+                    distances.push((self.descent_similarity(cur_genome, genome), *cur_genome));
                 }
-                let best_genome = *distances.iter().max_by_key(|a|a.0).unwrap(); //(similarity, ref), the best genome
+                let best_genome = *distances.iter().max_by(|a, b| a.0.total_cmp(&b.0)).unwrap(); //(similarity, ref), the best genome
+                log_debug!("descending from node {} ({} genomes), best candidate {} with similarity {}", cur.id, num_checked, best_genome.1.name(), best_genome.0);
+                steps.push(DescentStep {
+                    node: cur.id,
+                    count: num_checked,
+                    candidates: distances.iter().map(|(sim, g)| (String::from(g.name()), *sim)).collect(),
+                    best: String::from(best_genome.1.name()),
+                });
                 let node_path = algorithms::get_full_path(&self.root, &best_genome.1.path)?; //get the full list of nodes leading to the genome's parent
 
                 // check each node to see if we've checked it or not
@@ -238,74 +417,258 @@ impl PhyloTree {
 
         }
 
-        // if we've successfully reached the final 8 genomes
-        // launch the insertion protocol
-        // -do real comparisons on all genomes
-        // -find the closest relative
-        // -resort the tree if need be, and insert the genome
-        let distances: Arc<Mutex<Vec<(usize, Vec<u8>)>>> = Arc::new(Mutex::new(Vec::new())); // (distance, &Genome)
+        Ok(Descent { candidates: genomes, steps, final_node: cur.id })
+    }
+
+    /// Measure the distance between the genome and every candidate, one thread per candidate
+    ///
+    /// Pairs where either genome is flagged as partial are compared over their best matching region only,
+    /// and pairs where either is flagged as circular are lined up first.
+    fn candidate_distances(&self, genome: &Genome, candidates: &[&Genome]) -> Result<DistanceList, PhyloError> {
+        let model = self.model;
+        let genome_flags = self.metadata.flags(genome.name());
+        let distances: Arc<Mutex<DistanceList>> = Arc::new(Mutex::new(Vec::new())); // (distance, path, name, segment matches)
 
-        let genome_str = fs::read_to_string(&genome.dir).map_err(|_| PhyloError::FileOpenError(String::from(&genome.dir)))?;
-        let mut threads: Vec<thread::JoinHandle<()>> = Vec::new();
+        let genome_segments = genome.segments()?;
+        let mut threads: Vec<thread::JoinHandle<Result<(), PhyloError>>> = Vec::new();
 
         // for each genome, generate a thread that runs the levenshtein algorithm
-        for cur_genome in genomes {
+        for cur_genome in candidates {
 
             // copy variables that we'll need in the closure
             let dist_arc = distances.clone();
-            let genome_str0 = genome_str.clone();
+            let segments0 = genome_segments.clone();
             let genome_path = cur_genome.path.clone();
+            let genome_name = String::from(cur_genome.name());
             let genome_dir = cur_genome.dir.clone();
+            let comparison = Comparison::between(genome_flags, self.metadata.flags(cur_genome.name()));
 
             // launch a new thread for levenshtein distance
             let cur_thread = thread::spawn( move || {
-                let genome_str1 = fs::read_to_string(&genome_dir).map_err(|_| PhyloError::FileOpenError(String::from(genome_dir))).unwrap();
-                let distance = algorithms::levenshtein(&genome_str0, &genome_str1);
-                dist_arc.lock().unwrap().push((distance,genome_path));
+                let segments1 = segments::read_segments(&genome_dir)?;
+                let (distance, matches) = model.segmented_distance(&segments0, &segments1, comparison);
+                dist_arc.lock().unwrap().push((distance, genome_path, genome_name, matches));
+                Ok(())
             });
             threads.push(cur_thread);
         }
 
         // rejoin all threads back together
         for thr in threads {
-            thr.join().map_err(|_| PhyloError::GenomeInsertError(String::from("Error joining threads after running Levenshtein")))?;
+            thr.join().map_err(|_| PhyloError::GenomeInsertError(String::from("Error joining threads after running Levenshtein")))??; //a genome file that can't be read fails the placement
         }
 
-        let distances = distances.lock().unwrap().clone();
-        if let Some((best_dist,best_genome_path)) = distances.iter().min_by_key(|a|a.0) { //(path, distance), the best genome
-            let best_genome_mut = retrieve_genome(&mut self.root, &best_genome_path)?;
+        let mut ret = distances.lock().unwrap().clone();
+        ret.sort_by(|a, b| a.1.cmp(&b.1)); //threads finish in any order, keep ties between distances decided the same way every run
+        Ok(ret)
+    }
 
-            // update our new genome
-            genome.closest_distance = *best_dist;
-            
-            // first retrieve the parent node of the CR
-            let mut relative_distance = genome.closest_distance as f64 / best_genome_mut.closest_distance as f64;
+    /// Decide which insertion case applies, returns the case and the ratio of the new distance to the closest relative's own distance
+    fn decide_case(distance: f64, relative_distance: f64, floor_size: usize) -> (PlacementCase, f64) {
+        let ratio = distance / relative_distance;
+        if ratio <= 0.85 {
+            // consider an alternative case for Case 1, where the existing genome is the only genome in its floor
+            if floor_size == 1 {
+                (PlacementCase::OnlyMember, ratio)
+            } else {
+                (PlacementCase::NewPair, ratio)
+            }
+        } else if ratio >= 1.17 {
+            (PlacementCase::NewBranch, ratio)
+        } else {
+            (PlacementCase::SameBranch, ratio)
+        }
+    }
 
-            // retrieve the parent node of the CR
-            let parent_node = algorithms::get_mut_node_and_increment(&mut self.root, &best_genome_path)?;
+    /// Work out where a genome would be placed next to its closest relative, without changing anything
+    fn placement_path(&self, relative_path: &[u8], case: PlacementCase, floor_size: usize) -> Vec<u8> {
+        let mut path = relative_path[..relative_path.len()-1].to_vec(); //remove the index
+        match case {
+            PlacementCase::First => return vec![0, 0],
+            PlacementCase::NewPair | PlacementCase::NewBranch => { //the genome goes to index 0 of the new branch
+                path.push(self.next_index + 1);
+                path.push(0);
+            },
+            PlacementCase::OnlyMember | PlacementCase::SameBranch => { //the genome goes to the end of the relative's floor
+                path.push(floor_size as u8);
+            }
+        }
+        path
+    }
 
-            // consider an alternative case for Case 1, where the existing genome is the only genome in its floor
-            if relative_distance <= 0.85 {
-                if let TreeVertex::Floor(ref mut f) = parent_node.vertex {
+    /// Find where a genome would be placed without changing the tree
+    ///
+    /// Runs the same descent and distance comparisons as push. The returned record lists the
+    /// final candidates sorted from closest to furthest, and the path the genome would receive.
+    pub fn query(&self, genome: &Genome) -> Result<PlacementRecord, PhyloError> {
+        let genome_name = String::from(genome.name());
+        log_debug!("querying genome {}", genome_name);
+
+        // an empty tree would take the genome as its first member
+        if let TreeVertex::Floor(f) = &self.root.vertex {
+            if f.is_empty() {
+                return Ok(PlacementRecord::first(genome_name, vec![0, 0]));
+            }
+        }
+
+        let descent = self.descend(genome)?;
+        let mut distances = self.candidate_distances(genome, &descent.candidates)?;
+        distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (best_dist, best_genome_path, closest_name, _) = distances.first()
+            .ok_or(PhyloError::GenomeInsertError(String::from("Distances vector was empty, could find no nodes to compare to")))?
+            .clone();
+
+        // look up the closest relative and the floor it lives in
+        let node_path = algorithms::get_full_path(&self.root, &best_genome_path)?;
+        let (floor_size, relative) = match &node_path[node_path.len()-1].vertex {
+            TreeVertex::Floor(f) => (f.len(), &f[best_genome_path[best_genome_path.len()-1] as usize]),
+            TreeVertex::Split(_) => return Err(PhyloError::SearchNodeError(String::from("query: closest relative's parent is not a floor"))),
+        };
+        let (case, ratio) = Self::decide_case(best_dist, relative.closest_distance, floor_size);
+        let (segments, driving_segment) = segment_report(&distances, &closest_name);
+
+        Ok(PlacementRecord {
+            genome: genome_name,
+            descent: descent.steps,
+            final_node: descent.final_node,
+            distances: distances.iter().map(|(d, _, n, _)| (n.clone(), *d)).collect(),
+            closest: Some(closest_name),
+            ratio: Some(ratio),
+            case,
+            path: self.placement_path(&best_genome_path, case, floor_size),
+            segments,
+            driving_segment,
+        })
+    }
+
+    /// Find the k genomes in the tree most similar to the given genome, ranked from closest to furthest
+    ///
+    /// Branches are explored best first, scored by the kmer similarity of a couple of genomes sampled
+    /// from each, and the search stops once enough genomes have been collected for a shortlist. With
+    /// refine set, the shortlist is ranked by the distance under the tree's model instead of kmer similarity.
+    pub fn nearest(&self, genome: &Genome, k: usize, refine: bool) -> Result<Vec<Neighbour>, PhyloError> {
+        let sequence = fs::read_to_string(&genome.dir).map_err(|_| PhyloError::FileOpenError(String::from(&genome.dir)))?;
+        let shortlist_size = (k * 2).max(8); //look at a few more genomes than asked for, the sampled scores are only estimates
+        let mut rng = algorithms::genome_rng(self.kmers.seed, genome.name());
+
+        // frontier of nodes to expand, ordered by score, with ties going to the node seen first
+        let mut nodes: Vec<&TreeNode> = vec![&self.root];
+        let mut frontier: BinaryHeap<(u32, std::cmp::Reverse<usize>)> = BinaryHeap::new();
+        frontier.push((u32::MAX, std::cmp::Reverse(0)));
+        let mut pool: Vec<(u32, &Genome)> = Vec::new(); //(similarity, genome) for every genome we reached
+
+        while let Some((score, std::cmp::Reverse(index))) = frontier.pop() {
+            if pool.len() >= shortlist_size { //the rest of the tree scored worse than what we already have
+                break;
+            }
+            let node = nodes[index];
+            log_trace!("nearest: expanding node {} ({} genomes, score {})", node.id, node.count, score);
 
-                    // if the length of the existing floor has only one member
-                    if f.len() == 1 {
-                        println!("------> CASE 1.1: Closest relative is the only genome in its branch, don't split");
-                        relative_distance = 1.0;
+            match &node.vertex {
+                TreeVertex::Floor(f) => { //score every genome on this floor exactly
+                    for cur_genome in f {
+                        pool.push((algorithms::kmer_hits(&cur_genome.kmers, &sequence), cur_genome));
+                    }
+                },
+                TreeVertex::Split(s) => { //score each branch by a couple of its genomes
+                    for child in s {
+                        if child.count == 0 {
+                            continue;
+                        }
+                        let sampled = child.find(2, &mut rng)?;
+                        let child_score = sampled.iter().map(|g| algorithms::kmer_hits(&g.kmers, &sequence)).max().unwrap_or(0);
+                        nodes.push(child);
+                        frontier.push((child_score, std::cmp::Reverse(nodes.len()-1)));
                     }
                 }
             }
+        }
+
+        // keep the most similar genomes as the shortlist
+        pool.sort_by_key(|a| std::cmp::Reverse(a.0));
+        pool.truncate(if refine { shortlist_size } else { k });
+        let mut ret: Vec<Neighbour> = pool.iter().map(|(sim, g)| Neighbour {
+            genome: String::from(g.name()),
+            path: g.path.clone(),
+            similarity: *sim,
+            distance: None,
+        }).collect();
+
+        // rank the shortlist by the real distance
+        if refine {
+            let candidates: Vec<&Genome> = pool.iter().map(|(_, g)| *g).collect();
+            let distances = self.candidate_distances(genome, &candidates)?;
+            for neighbour in &mut ret {
+                neighbour.distance = distances.iter().find(|(_, p, _, _)| *p == neighbour.path).map(|(d, _, _, _)| *d);
+            }
+            ret.sort_by(|a, b| {
+                let (da, db) = (a.distance.unwrap_or(f64::INFINITY), b.distance.unwrap_or(f64::INFINITY));
+                da.total_cmp(&db).then(b.similarity.cmp(&a.similarity))
+            });
+            ret.truncate(k);
+        }
+        Ok(ret)
+    }
 
-            // CASE 1 TODO consider the case where the closest relative is the only genome in that branch, simply place new genome there
-            if relative_distance <= 0.85 { //create a new branch, bring the new genome and its closest relative into it, update genome paths
-                println!("---> CASE 1: New branch, put genome and closest relative there");
+    /// Push a new genome onto the tree, returning a record of how it was placed
+    pub fn push(&mut self, mut genome: Genome) -> Result<PlacementRecord, PhyloError>{
+        let genome_name = String::from(genome.name());
+        log_debug!("pushing genome {}", genome_name);
 
-                println!("DEBUGGING SPLIT FOR CASE 1");
-                println!("Before:");
-                dbg!(&parent_node);
+        let root_count_increment: bool;
+
+        // if we have an empty tree, just push it
+        if let TreeVertex::Floor(s) = &mut self.root.vertex {
+            genome.path = vec![0, 0];
+            genome.closest_distance = f64::INFINITY;
+            if s.is_empty() {
+                log_info!("placed {} (case 0, first genome)", genome_name);
+                let record = PlacementRecord::first(genome_name, genome.path.clone());
+                if logging::events_enabled() {
+                    logging::event("placement", record.to_json());
+                }
+                s.push(genome);
+                self.root.count = 1;
+                return Ok(record);
+            }
+            root_count_increment = true;
+        } else {
+            root_count_increment = false;
+        }
+
+        // if we've successfully reached the final 8 genomes
+        // launch the insertion protocol
+        // -do real comparisons on all genomes
+        // -find the closest relative
+        // -resort the tree if need be, and insert the genome
+        let descent = self.descend(&genome)?;
+        let distances = self.candidate_distances(&genome, &descent.candidates)?;
+        let (steps, final_node) = (descent.steps, descent.final_node);
+
+        if let Some((best_dist, best_genome_path, closest_name, _)) = distances.iter().min_by(|a, b| a.0.total_cmp(&b.0)) { //(distance, path, name, segment matches), the best genome
+            let best_genome_mut = retrieve_genome(&mut self.root, best_genome_path)?;
+
+            // update our new genome
+            genome.closest_distance = *best_dist;
+            let relative_distance = best_genome_mut.closest_distance;
+            let mut placed_path: Vec<u8> = Vec::new(); //where the new genome ended up
+
+            // retrieve the parent node of the CR
+            let parent_node = algorithms::get_mut_node_and_increment(&mut self.root, best_genome_path)?;
+            let floor_size = match &parent_node.vertex {
+                TreeVertex::Floor(f) => f.len(),
+                TreeVertex::Split(_) => 0,
+            };
+            let (case, ratio) = Self::decide_case(genome.closest_distance, relative_distance, floor_size);
+            if case == PlacementCase::OnlyMember {
+                log_debug!("closest relative is the only genome in its branch, not splitting (case 1.1)");
+            }
+
+            // CASE 1
+            if case == PlacementCase::NewPair { //create a new branch, bring the new genome and its closest relative into it, update genome paths
+                log_trace!("before split: {:#?}", parent_node);
                 parent_node.split(self.next_index); //turn the floor into a split with one child
-                println!("After:");
-                dbg!(&parent_node);
+                log_trace!("after split: {:#?}", parent_node);
 
                 // open the split
                 if let TreeVertex::Split(ref mut s) = parent_node.vertex {
@@ -319,6 +682,7 @@ impl PhyloTree {
                     genome_path.push(0); //the new genome will be added to index 0
                     cr_path.push(1); //the cr genome will be added to index 1
                     genome.path = genome_path.clone();
+                    placed_path = genome_path.clone();
                     let mut closest_relative;
 
                     // create the second branch, where the new genome and its CR will reside
@@ -337,8 +701,7 @@ impl PhyloTree {
                         closest_relative = f_original.remove(best_genome_path[best_genome_path.len()-1] as usize); //grab the closest relative Genome so we can move it
 
                         // update all the paths because the vector was just shifted and a split was created
-                        for i in 0..f_original.len() {
-                            let mut cur_genome = &mut f_original[i];
+                        for (i, cur_genome) in f_original.iter_mut().enumerate() {
                             let mut new_path = cur_genome.path.clone();
                             new_path.remove(new_path.len()-1); //remove the index
                             new_path.push(self.next_index); //push the new split
@@ -350,7 +713,10 @@ impl PhyloTree {
                             path: Vec::new(),
                             dir: String::from(""),
                             kmers: Vec::new(),
-                            closest_distance: 0,
+                            sketch: Sketch::default(),
+                            spectrum: KmerSpectrum::default(),
+                            closest_distance: 0.0,
+                            length: None,
                         }
                     }
 
@@ -366,18 +732,13 @@ impl PhyloTree {
                     }
 
                 }
-                self.next_index = self.next_index + 2; //added two new nodes
+                self.next_index += 2; //added two new nodes
 
             // CASE 2
-            } else if relative_distance >= 1.17 { //create a new branch, place the new genome there
-                println!("---> CASE 2: New branch, put genome there");
-
-                println!("DEBUGGING SPLIT FOR CASE 1");
-                println!("Before:");
-                dbg!(&parent_node);
+            } else if case == PlacementCase::NewBranch { //create a new branch, place the new genome there
+                log_trace!("before split: {:#?}", parent_node);
                 parent_node.split(self.next_index);
-                println!("After:");
-                dbg!(&parent_node);
+                log_trace!("after split: {:#?}", parent_node);
 
                 // retrieve the parent node above where the floor node is
                 if let TreeVertex::Split(ref mut s) = parent_node.vertex {
@@ -392,6 +753,7 @@ impl PhyloTree {
                     cr_path.insert(cr_path.len()-1, self.next_index); //push the new split
                     
                     genome.path = genome_path.clone();
+                    placed_path = genome_path.clone();
 
                     // create the second branch, where the new genome will reside
                     s.push(TreeNode::new_with_floor(self.next_index + 1, 1)); //there will only be the new genome, so count 1
@@ -415,12 +777,10 @@ impl PhyloTree {
                         f.push(genome);
                     }
                 }
-                self.next_index = self.next_index + 2; //added two new nodes
+                self.next_index += 2; //added two new nodes
 
             // CASE 3
             } else { //place the new genome in the same branch as its closest relative
-                println!("---> CASE 3: Put genome in same branch as closest relative");
-
                 parent_node.count += 1;
 
                 if let TreeVertex::Floor(ref mut f) = parent_node.vertex {
@@ -432,6 +792,7 @@ impl PhyloTree {
                     if closest_relative.closest_distance > genome.closest_distance { //if we need to update cr's closest distance, do it here
                         closest_relative.closest_distance = genome.closest_distance;
                     }
+                    placed_path = new_path.clone();
                     genome.path = new_path;
                     f.push(genome);
                 }
@@ -441,10 +802,88 @@ impl PhyloTree {
             if root_count_increment {
                 //self.root.count += 1;
             }
-            return Ok(());
+
+            log_info!("placed {} next to {} (case {}, distance {}, ratio {:.3})", genome_name, closest_name, case.label(), best_dist, ratio);
+            let (segments, driving_segment) = segment_report(&distances, closest_name);
+            let record = PlacementRecord {
+                genome: genome_name,
+                descent: steps,
+                final_node,
+                distances: distances.iter().map(|(d, _, n, _)| (n.clone(), *d)).collect(),
+                closest: Some(closest_name.clone()),
+                ratio: Some(ratio),
+                case,
+                path: placed_path,
+                segments,
+                driving_segment,
+            };
+            let reassorted = record.reassorted();
+            if !reassorted.is_empty() {
+                log_info!("{} of {} segments of {} are closer to other genomes than {}", reassorted.len(), record.segments.len(), record.genome, closest_name);
+            }
+            if logging::events_enabled() {
+                logging::event("placement", record.to_json());
+            }
+            return Ok(record);
         }
 
         Err(PhyloError::GenomeInsertError(String::from("Distances vector was empty, could find no nodes to compare to")))
     }
 }
 
+
+/// Read the file of every genome under the node again, and give each its closest distance from the report
+fn load_genomes(node: &mut TreeNode, kmers: &KmerOptions, closest: &HashMap<String, f64>) -> Result<(), PhyloError> {
+    match &mut node.vertex {
+        TreeVertex::Split(s) => {
+            for child in s {
+                load_genomes(child, kmers, closest)?;
+            }
+        },
+        TreeVertex::Floor(f) => {
+            for genome in f {
+                let loaded = Genome::load(&genome.dir, kmers)?;
+                genome.kmers = loaded.kmers;
+                genome.sketch = loaded.sketch;
+                genome.spectrum = loaded.spectrum;
+                genome.closest_distance = closest.get(genome.name()).copied().unwrap_or(f64::INFINITY);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// How every segment of a placed genome matched the candidates, and which segment drove the placement
+fn segment_report(distances: &DistanceList, closest: &str) -> (Vec<SegmentPlacement>, Option<String>) {
+    let candidates: Vec<(&str, &[SegmentMatch])> = distances.iter().map(|(_, _, name, matches)| (name.as_str(), matches.as_slice())).collect();
+    SegmentPlacement::from_candidates(&candidates, closest)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_of_floors(floors: usize) -> TreeNode {
+        let mut ret = TreeNode::new_with_floor(0, 0);
+        ret.vertex = TreeVertex::new_split();
+        for _ in 0..floors {
+            ret.vertex.push_node(TreeNode::new_with_floor(0, 0));
+        }
+        ret
+    }
+
+    #[test]
+    fn renumber_fails_instead_of_wrapping() {
+        assert_eq!(split_of_floors(254).renumber(0).unwrap(), 255);
+        assert!(split_of_floors(255).renumber(0).is_err());
+    }
+
+    #[test]
+    fn genome_names_come_from_folders() {
+        assert_eq!(genome_name("genomes/Abaca_virus/sequence.fna"), "Abaca_virus");
+        assert_eq!(genome_name("genomes/Abaca_virus/"), "Abaca_virus");
+        assert_eq!(genome_name("sequence.fna"), "sequence.fna");
+    }
+}